@@ -0,0 +1,80 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of telegram-sms-termux and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Battery level chart, rendered as Unicode sparkline.
+
+use crate::database::battery_history::BatteryRecord;
+
+const SPARKS: &[char] = &['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Average level of each bucket between `since` and `until`, `None` if bucket
+/// has no record
+pub fn bucket_levels(
+    records: &[BatteryRecord],
+    since: i64,
+    until: i64,
+    buckets: usize,
+) -> Vec<Option<f64>> {
+    let span = (until - since).max(1);
+    let mut sums = vec![(0.0, 0); buckets];
+    for record in records {
+        if record.timestamp < since || record.timestamp >= until {
+            continue;
+        }
+        let index = ((record.timestamp - since) * buckets as i64 / span) as usize;
+        sums[index].0 += record.percentage as f64;
+        sums[index].1 += 1;
+    }
+    sums.into_iter()
+        .map(|(sum, count)| (count > 0).then(|| sum / count as f64))
+        .collect()
+}
+
+pub fn sparkline(levels: &[Option<f64>]) -> String {
+    levels
+        .iter()
+        .map(|level| match level {
+            Some(level) => {
+                SPARKS
+                    [(level.clamp(0.0, 100.0) / 100.0 * (SPARKS.len() - 1) as f64).round() as usize]
+            }
+            None => ' ',
+        })
+        .collect()
+}
+
+/// First and last level, level range and temperature range
+pub fn summary(records: &[BatteryRecord]) -> Option<String> {
+    let (first, last) = (records.first()?, records.last()?);
+    let min = records.iter().map(|record| record.percentage).min()?;
+    let max = records.iter().map(|record| record.percentage).max()?;
+    let min_temperature = records
+        .iter()
+        .map(|record| record.temperature)
+        .fold(f64::INFINITY, f64::min);
+    let max_temperature = records
+        .iter()
+        .map(|record| record.temperature)
+        .fold(f64::NEG_INFINITY, f64::max);
+    Some(format!(
+        "Level: {}% → {}% (min {}%, max {}%)\nTemperature: {:.1}°C - {:.1}°C",
+        first.percentage, last.percentage, min, max, min_temperature, max_temperature
+    ))
+}
@@ -17,9 +17,9 @@
  ** You should have received a copy of the GNU Affero General Public License
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
-#[allow(dead_code)]
 pub mod v1 {
 
+    #[cfg(test)]
     pub const CREATE_STATEMENTS: &str = r#"
     CREATE TABLE "call_logs" (
         "identifier" TEXT NOT NULL,
@@ -48,18 +48,1586 @@ pub mod v1 {
     
     INSERT INTO "client_meta" VALUES ('version', '1');
     "#;
+}
+
+pub mod v2 {
+
+    #[cfg(test)]
+    pub const CREATE_STATEMENTS: &str = r#"
+    CREATE TABLE "call_logs" (
+        "identifier" TEXT NOT NULL,
+        "timestamp" INTEGER NOT NULL,
+        PRIMARY KEY("identifier")
+    );
+
+
+    CREATE TABLE "messages" (
+        "identifier" TEXT NOT NULL,
+        "timestamp" INTEGER NOT NULL,
+        PRIMARY KEY("identifier")
+    );
+
+    CREATE TABLE "notifications" (
+        "identifier" TEXT NOT NULL,
+        "timestamp" INTEGER NOT NULL,
+        PRIMARY KEY("identifier")
+    );
+
+    CREATE TABLE "notification_messages" (
+        "key" TEXT NOT NULL,
+        "message_id" INTEGER NOT NULL,
+        "timestamp" INTEGER NOT NULL,
+        PRIMARY KEY("key")
+    );
+
+    CREATE TABLE "client_meta" (
+        "key"	TEXT NOT NULL,
+        "value"	TEXT NOT NULL,
+        PRIMARY KEY("key")
+    );
+
+    INSERT INTO "client_meta" VALUES ('version', '2');
+    "#;
+
+    /// Upgrade from v1
+    pub const UPGRADE_STATEMENTS: &str = r#"
+    CREATE TABLE "notification_messages" (
+        "key" TEXT NOT NULL,
+        "message_id" INTEGER NOT NULL,
+        "timestamp" INTEGER NOT NULL,
+        PRIMARY KEY("key")
+    );
+
+    UPDATE "client_meta" SET "value" = '2' WHERE "key" = 'version';
+    "#;
+
+    #[cfg(test)]
+    pub const VERSION: &str = "2";
+}
+
+/// Identifier v2 for messages and call logs, identifiers v1 stored before
+/// `legacy_identifier_until` are still recognized.
+pub mod v3 {
+
+    /// Upgrade from v2
+    pub const UPGRADE_STATEMENTS: &str = r#"
+    INSERT OR REPLACE INTO "client_meta" VALUES ('legacy_identifier_until', CAST(strftime('%s', 'now') AS TEXT));
+
+    UPDATE "client_meta" SET "value" = '3' WHERE "key" = 'version';
+    "#;
+
+    /// `client_meta` key of the time upgraded from v2
+    pub const LEGACY_IDENTIFIER_UNTIL: &str = "legacy_identifier_until";
+}
+
+/// Archive of SMS and call log content
+pub mod v4 {
+
+    /// Upgrade from v3
+    pub const UPGRADE_STATEMENTS: &str = r#"
+    CREATE TABLE "message_archive" (
+        "identifier" TEXT NOT NULL,
+        "thread_id" INTEGER NOT NULL,
+        "box" TEXT NOT NULL,
+        "read" INTEGER NOT NULL,
+        "number" TEXT NOT NULL,
+        "contact_name" TEXT,
+        "body" TEXT NOT NULL,
+        "sim_id" TEXT,
+        "timestamp" INTEGER NOT NULL,
+        "local_timestamp" INTEGER NOT NULL,
+        PRIMARY KEY("identifier")
+    );
+
+    CREATE TABLE "call_log_archive" (
+        "identifier" TEXT NOT NULL,
+        "type" TEXT NOT NULL,
+        "number" TEXT NOT NULL,
+        "contact_name" TEXT NOT NULL,
+        "duration" TEXT NOT NULL,
+        "sim_id" TEXT,
+        "timestamp" INTEGER NOT NULL,
+        "local_timestamp" INTEGER NOT NULL,
+        PRIMARY KEY("identifier")
+    );
+
+    UPDATE "client_meta" SET "value" = '4' WHERE "key" = 'version';
+    "#;
+}
+
+/// Full-text index of SMS bodies and senders
+pub mod v5 {
+
+    /// Upgrade from v4, archived messages are indexed
+    pub const UPGRADE_STATEMENTS: &str = r#"
+    CREATE VIRTUAL TABLE "message_search" USING fts5(
+        "identifier" UNINDEXED,
+        "number",
+        "contact_name",
+        "body",
+        "timestamp" UNINDEXED
+    );
+
+    INSERT INTO "message_search"
+        SELECT "identifier", "number", "contact_name", "body", "timestamp" FROM "message_archive";
+
+    UPDATE "client_meta" SET "value" = '5' WHERE "key" = 'version';
+    "#;
+}
+
+pub mod v6 {
+
+    pub const CREATE_STATEMENTS: &str = r#"
+    CREATE TABLE "call_logs" (
+        "identifier" TEXT NOT NULL,
+        "timestamp" INTEGER NOT NULL,
+        PRIMARY KEY("identifier")
+    );
+
+
+    CREATE TABLE "messages" (
+        "identifier" TEXT NOT NULL,
+        "timestamp" INTEGER NOT NULL,
+        PRIMARY KEY("identifier")
+    );
+
+    CREATE TABLE "notifications" (
+        "identifier" TEXT NOT NULL,
+        "timestamp" INTEGER NOT NULL,
+        PRIMARY KEY("identifier")
+    );
+
+    CREATE TABLE "notification_messages" (
+        "key" TEXT NOT NULL,
+        "message_id" INTEGER NOT NULL,
+        "timestamp" INTEGER NOT NULL,
+        PRIMARY KEY("key")
+    );
+
+    CREATE TABLE "message_archive" (
+        "identifier" TEXT NOT NULL,
+        "thread_id" INTEGER NOT NULL,
+        "box" TEXT NOT NULL,
+        "read" INTEGER NOT NULL,
+        "number" TEXT NOT NULL,
+        "contact_name" TEXT,
+        "body" TEXT NOT NULL,
+        "sim_id" TEXT,
+        "timestamp" INTEGER NOT NULL,
+        "local_timestamp" INTEGER NOT NULL,
+        PRIMARY KEY("identifier")
+    );
+
+    CREATE TABLE "call_log_archive" (
+        "identifier" TEXT NOT NULL,
+        "type" TEXT NOT NULL,
+        "number" TEXT NOT NULL,
+        "contact_name" TEXT NOT NULL,
+        "duration" TEXT NOT NULL,
+        "sim_id" TEXT,
+        "timestamp" INTEGER NOT NULL,
+        "local_timestamp" INTEGER NOT NULL,
+        PRIMARY KEY("identifier")
+    );
+
+    CREATE VIRTUAL TABLE "message_search" USING fts5(
+        "identifier" UNINDEXED,
+        "number",
+        "contact_name",
+        "body",
+        "timestamp" UNINDEXED
+    );
+
+    CREATE TABLE "client_meta" (
+        "key"	TEXT NOT NULL,
+        "value"	TEXT NOT NULL,
+        PRIMARY KEY("key")
+    );
+
+    CREATE TABLE "battery_history" (
+        "timestamp" INTEGER NOT NULL,
+        "percentage" INTEGER NOT NULL,
+        "status" TEXT NOT NULL,
+        "plugged" TEXT NOT NULL,
+        "temperature" REAL NOT NULL,
+        "current" INTEGER NOT NULL,
+        "resolution" INTEGER NOT NULL
+    );
+
+    CREATE INDEX "battery_history_timestamp" ON "battery_history" ("timestamp");
+
+    INSERT INTO "client_meta" VALUES ('version', '6');
+    "#;
+
+    /// Upgrade from v5, polled battery status is recorded
+    pub const UPGRADE_STATEMENTS: &str = r#"
+    CREATE TABLE "battery_history" (
+        "timestamp" INTEGER NOT NULL,
+        "percentage" INTEGER NOT NULL,
+        "status" TEXT NOT NULL,
+        "plugged" TEXT NOT NULL,
+        "temperature" REAL NOT NULL,
+        "current" INTEGER NOT NULL,
+        "resolution" INTEGER NOT NULL
+    );
+
+    CREATE INDEX "battery_history_timestamp" ON "battery_history" ("timestamp");
 
-    pub const DROP_STATEMENTS: &str = r#"
-    DROP TABLE "call_logs";
-    DROP TABLE "messages";
-    DROP TABLE "notifications";
-    DROP TABLE "client_meta";
+    UPDATE "client_meta" SET "value" = '6' WHERE "key" = 'version';
     "#;
 
-    pub const VERSION: &str = "1";
+    pub const VERSION: &str = "6";
 
     pub const META_TABLE: &str = "client_meta";
+
+    pub use super::v3::LEGACY_IDENTIFIER_UNTIL;
+}
+
+pub use v6 as current;
+pub use v6::VERSION as CURRENT_VERSION;
+
+pub mod migration {
+    use anyhow::{anyhow, Result};
+    use sqlx::{sqlite::SqliteConnection, Connection};
+
+    /// Statements which upgrade schema `from` to `to`, they should update
+    /// version in `client_meta` too.
+    pub struct Migration {
+        pub from: u32,
+        pub to: u32,
+        pub statements: &'static str,
+    }
+
+    /// Ordered up-migrations, last one must upgrade to `CURRENT_VERSION`
+    pub const MIGRATIONS: &[Migration] = &[
+        Migration {
+            from: 1,
+            to: 2,
+            statements: super::v2::UPGRADE_STATEMENTS,
+        },
+        Migration {
+            from: 2,
+            to: 3,
+            statements: super::v3::UPGRADE_STATEMENTS,
+        },
+        Migration {
+            from: 3,
+            to: 4,
+            statements: super::v4::UPGRADE_STATEMENTS,
+        },
+        Migration {
+            from: 4,
+            to: 5,
+            statements: super::v5::UPGRADE_STATEMENTS,
+        },
+        Migration {
+            from: 5,
+            to: 6,
+            statements: super::v6::UPGRADE_STATEMENTS,
+        },
+    ];
+
+    /// Schema version in `client_meta`, `None` if database is not initialized
+    pub async fn get_version(conn: &mut SqliteConnection) -> Result<Option<u32>> {
+        let initialized =
+            sqlx::query(r#"SELECT name FROM sqlite_master WHERE type='table' AND "name"=?"#)
+                .bind(super::current::META_TABLE)
+                .fetch_optional(&mut *conn)
+                .await?
+                .is_some();
+        if !initialized {
+            return Ok(None);
+        }
+        let (version,): (String,) =
+            sqlx::query_as(r#"SELECT "value" FROM "client_meta" WHERE "key" = 'version'"#)
+                .fetch_one(&mut *conn)
+                .await?;
+        Ok(Some(version.parse().map_err(|e| {
+            anyhow!("Unable to parse database version {:?}: {}", version, e)
+        })?))
+    }
+
+    /// Upgrade database to `CURRENT_VERSION`, each migration is applied in its
+    /// own transaction. Database file at `database_path` is copied to
+    /// `<database_path>.v<version>.<timestamp>.bak` before the first migration.
+    pub async fn migrate(conn: &mut SqliteConnection, database_path: Option<&str>) -> Result<()> {
+        let current: u32 = super::CURRENT_VERSION.parse()?;
+        let mut version = match get_version(conn).await? {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+        if version > current {
+            return Err(anyhow!(
+                "Database version {} is newer than supported version {}, please upgrade client",
+                version,
+                current
+            ));
+        }
+        if version == current {
+            return Ok(());
+        }
+
+        if let Some(database_path) = database_path {
+            let path = format!(
+                "{}.v{}.{}.bak",
+                database_path,
+                version,
+                chrono::Utc::now().timestamp()
+            );
+            log::info!("Backup database version {} to {}", version, path);
+            sqlx::query("VACUUM INTO ?")
+                .bind(&path)
+                .execute(&mut *conn)
+                .await?;
+        }
+
+        while version < current {
+            let migration = MIGRATIONS
+                .iter()
+                .find(|migration| migration.from == version)
+                .ok_or_else(|| anyhow!("No migration from database version {}", version))?;
+            log::info!(
+                "Upgrade database from version {} to {}",
+                migration.from,
+                migration.to
+            );
+            let mut transaction = conn.begin().await?;
+            sqlx::query(migration.statements)
+                .execute(&mut transaction)
+                .await?;
+            transaction.commit().await?;
+            version = migration.to;
+        }
+        Ok(())
+    }
+}
+
+/// Full content of SMS and call logs, kept if archive is enabled
+pub mod archive {
+    use super::crypto::Cipher;
+    use crate::datastructures::{CallLog, Identifier, Message};
+    use sqlx::sqlite::SqliteConnection;
+
+    /// Read flag of archived message is updated if it is stored before,
+    /// `identifier` should be hashed by `cipher` already.
+    pub async fn insert_message(
+        conn: &mut SqliteConnection,
+        cipher: &Cipher,
+        identifier: &str,
+        message: &Message,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"INSERT OR REPLACE INTO "message_archive" VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(identifier)
+        .bind(message.get_thread_id() as i64)
+        .bind(message.get_box().as_str())
+        .bind(message.is_read())
+        .bind(cipher.seal(message.get_number()))
+        .bind(message.get_sender().map(|sender| cipher.seal(sender)))
+        .bind(cipher.seal(message.get_content()))
+        .bind(message.get_sim_id())
+        .bind(message.get_timestamp())
+        .bind(message.get_local_timestamp())
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn insert_call_log(
+        conn: &mut SqliteConnection,
+        cipher: &Cipher,
+        identifier: &str,
+        call_log: &CallLog,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(r#"INSERT OR IGNORE INTO "call_log_archive" VALUES (?, ?, ?, ?, ?, ?, ?, ?)"#)
+            .bind(identifier)
+            .bind(call_log.get_log_type().as_str())
+            .bind(cipher.seal(call_log.get_number()))
+            .bind(cipher.seal(call_log.get_name()))
+            .bind(call_log.get_duration())
+            .bind(call_log.get_sim_id())
+            .bind(call_log.get_timestamp())
+            .bind(call_log.get_local_timestamp())
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Full-text search over SMS, query is FTS5 syntax with extra filters:
+/// `from:<number or contact>`, `since:<YYYY-MM-DD>`, `until:<YYYY-MM-DD>` and
+/// `page:<n>`. Encrypted database has no index, terms are matched against
+/// decrypted archive instead.
+pub mod search {
+    use anyhow::{anyhow, Result};
+    use chrono_tz::Tz;
+    use sqlx::sqlite::SqliteConnection;
+
+    use super::crypto::Cipher;
+    use crate::datastructures::{
+        convert_string_to_timestamp, format_timestamp, Identifier, Message,
+    };
+
+    pub const PAGE_SIZE: usize = 5;
+    /// Rows of encrypted archive decrypted by each query while searching
+    const SCAN_PAGE_SIZE: usize = 100;
+
+    pub async fn index_message(
+        conn: &mut SqliteConnection,
+        identifier: &str,
+        message: &Message,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(r#"INSERT INTO "message_search" VALUES (?, ?, ?, ?, ?)"#)
+            .bind(identifier)
+            .bind(message.get_number())
+            .bind(message.get_sender())
+            .bind(message.get_content())
+            .bind(message.get_timestamp())
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct SearchQuery {
+        /// FTS5 match expression, phrase queries are written in double quotes
+        text: String,
+        sender: Option<String>,
+        /// Inclusive UTC timestamp
+        since: Option<i64>,
+        /// Exclusive UTC timestamp
+        until: Option<i64>,
+        /// Starts from 1
+        page: usize,
+    }
+
+    /// Split by whitespace, double quoted phrase is kept as one term
+    fn split_terms(s: &str) -> Vec<String> {
+        let mut terms = Vec::new();
+        let mut current = String::new();
+        let mut quoted = false;
+        for c in s.chars() {
+            match c {
+                '"' => {
+                    quoted = !quoted;
+                    current.push(c);
+                }
+                c if c.is_whitespace() && !quoted => {
+                    if !current.is_empty() {
+                        terms.push(std::mem::take(&mut current));
+                    }
+                }
+                c => current.push(c),
+            }
+        }
+        if !current.is_empty() {
+            terms.push(current);
+        }
+        terms
+    }
+
+    fn parse_date(date: &str, timezone: &Tz) -> Result<i64> {
+        Ok(
+            convert_string_to_timestamp(&format!("{} 00:00:00", date), timezone)
+                .map_err(|_| anyhow!("Unable to parse date {:?}, expect YYYY-MM-DD", date))?
+                .0,
+        )
+    }
+
+    impl SearchQuery {
+        pub fn parse(s: &str, timezone: &Tz) -> Result<Self> {
+            let mut query = SearchQuery {
+                page: 1,
+                ..Default::default()
+            };
+            let mut text = Vec::new();
+            for term in split_terms(s) {
+                if let Some(sender) = term.strip_prefix("from:") {
+                    query.sender = Some(sender.trim_matches('"').to_string());
+                } else if let Some(date) = term.strip_prefix("since:") {
+                    query.since = Some(parse_date(date, timezone)?);
+                } else if let Some(date) = term.strip_prefix("until:") {
+                    query.until = Some(parse_date(date, timezone)? + 86400);
+                } else if let Some(page) = term.strip_prefix("page:") {
+                    query.page = page
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|page| *page > 0)
+                        .ok_or_else(|| anyhow!("Invalid page {:?}", page))?;
+                } else {
+                    text.push(term);
+                }
+            }
+            query.text = text.join(" ");
+            Ok(query)
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct SearchResult {
+        pub number: String,
+        pub contact_name: Option<String>,
+        /// Matched terms are surrounded by `«` and `»`
+        pub snippet: String,
+        pub timestamp: i64,
+    }
+
+    /// Newest messages first, return one more result than `PAGE_SIZE` if there
+    /// is next page. Encrypted database is not indexed, so only its archive
+    /// can be searched.
+    pub async fn search(
+        conn: &mut SqliteConnection,
+        cipher: &Cipher,
+        archive: bool,
+        query: &SearchQuery,
+    ) -> Result<Vec<SearchResult>> {
+        if cipher.is_enabled() {
+            if !archive {
+                return Err(anyhow!(
+                    "Search on encrypted database requires archive = true"
+                ));
+            }
+            return scan_archive(conn, cipher, query).await;
+        }
+        let mut conditions = Vec::new();
+        if !query.text.is_empty() {
+            conditions.push(r#""message_search" MATCH ?"#);
+        }
+        if query.sender.is_some() {
+            conditions.push(r#"("number" LIKE ? ESCAPE '\' OR "contact_name" LIKE ? ESCAPE '\')"#);
+        }
+        if query.since.is_some() {
+            conditions.push(r#""timestamp" >= ?"#);
+        }
+        if query.until.is_some() {
+            conditions.push(r#""timestamp" < ?"#);
+        }
+        let snippet = if query.text.is_empty() {
+            r#"substr("body", 1, 100)"#
+        } else {
+            r#"snippet("message_search", 3, '«', '»', '…', 16)"#
+        };
+        let sql = format!(
+            r#"SELECT "number", "contact_name", {}, "timestamp" FROM "message_search" {} ORDER BY "timestamp" DESC LIMIT ? OFFSET ?"#,
+            snippet,
+            if conditions.is_empty() {
+                String::new()
+            } else {
+                format!("WHERE {}", conditions.join(" AND "))
+            }
+        );
+        let mut statement = sqlx::query_as::<_, (String, Option<String>, String, i64)>(&sql);
+        if !query.text.is_empty() {
+            statement = statement.bind(&query.text);
+        }
+        if let Some(ref sender) = query.sender {
+            let pattern = format!(
+                "%{}%",
+                sender
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_")
+            );
+            statement = statement.bind(pattern.clone()).bind(pattern);
+        }
+        if let Some(since) = query.since {
+            statement = statement.bind(since);
+        }
+        if let Some(until) = query.until {
+            statement = statement.bind(until);
+        }
+        let rows = statement
+            .bind((PAGE_SIZE + 1) as i64)
+            .bind(((query.page - 1) * PAGE_SIZE) as i64)
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(|e| anyhow!("Unable to search {:?}: {}", query.text, e))?;
+        Ok(rows
+            .into_iter()
+            .map(|(number, contact_name, snippet, timestamp)| SearchResult {
+                number,
+                contact_name,
+                snippet,
+                timestamp,
+            })
+            .collect())
+    }
+
+    /// Every term, quoted phrase included, should appear in number, contact
+    /// name or body, FTS5 operators are not supported. Archive is decrypted
+    /// `SCAN_PAGE_SIZE` rows at a time until requested page is filled.
+    async fn scan_archive(
+        conn: &mut SqliteConnection,
+        cipher: &Cipher,
+        query: &SearchQuery,
+    ) -> Result<Vec<SearchResult>> {
+        let terms: Vec<String> = split_terms(&query.text)
+            .iter()
+            .map(|term| term.trim_matches('"').to_lowercase())
+            .collect();
+        let sender = query.sender.as_ref().map(|sender| sender.to_lowercase());
+        let mut skip = (query.page - 1) * PAGE_SIZE;
+        let mut results = Vec::new();
+        let mut offset = 0;
+        loop {
+            let rows: Vec<(String, Option<String>, String, i64)> = sqlx::query_as(
+                r#"SELECT "number", "contact_name", "body", "timestamp" FROM "message_archive" WHERE "timestamp" >= ? AND "timestamp" < ? ORDER BY "timestamp" DESC, "rowid" DESC LIMIT ? OFFSET ?"#,
+            )
+            .bind(query.since.unwrap_or(i64::MIN))
+            .bind(query.until.unwrap_or(i64::MAX))
+            .bind(SCAN_PAGE_SIZE as i64)
+            .bind(offset as i64)
+            .fetch_all(&mut *conn)
+            .await?;
+            offset += rows.len();
+            let last_page = rows.len() < SCAN_PAGE_SIZE;
+            for (number, contact_name, body, timestamp) in rows {
+                let number = cipher.open(&number)?;
+                let contact_name = contact_name.map(|name| cipher.open(&name)).transpose()?;
+                let body = cipher.open(&body)?;
+                let name = contact_name.as_deref().unwrap_or_default().to_lowercase();
+                let is_sender_match = sender.as_ref().map_or(true, |sender| {
+                    number.to_lowercase().contains(sender) || name.contains(sender)
+                });
+                let content = format!("{} {} {}", number, name, body).to_lowercase();
+                if !is_sender_match || !terms.iter().all(|term| content.contains(term)) {
+                    continue;
+                }
+                if skip > 0 {
+                    skip -= 1;
+                    continue;
+                }
+                results.push(SearchResult {
+                    number,
+                    contact_name,
+                    snippet: body.chars().take(100).collect(),
+                    timestamp,
+                });
+                if results.len() > PAGE_SIZE {
+                    return Ok(results);
+                }
+            }
+            if last_page {
+                return Ok(results);
+            }
+        }
+    }
+
+    pub fn format_results(query: &SearchQuery, results: &[SearchResult], timezone: &Tz) -> String {
+        if results.is_empty() {
+            return format!("[Search] No result on page {}", query.page);
+        }
+        let mut text = format!("[Search] Page {}", query.page);
+        for result in results.iter().take(PAGE_SIZE) {
+            text.push_str(&format!(
+                "\n\n{} {}{}\n{}",
+                format_timestamp(result.timestamp, timezone),
+                result.number,
+                result
+                    .contact_name
+                    .as_ref()
+                    .map(|name| format!(" ({})", name))
+                    .unwrap_or_default(),
+                result.snippet
+            ));
+        }
+        if results.len() > PAGE_SIZE {
+            text.push_str(&format!("\n\nMore results with page:{}", query.page + 1));
+        }
+        text
+    }
+}
+
+/// Pruning of seen-identifier tables
+pub mod retention {
+    use anyhow::Result;
+    use sqlx::sqlite::SqliteConnection;
+
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct RetentionPolicy {
+        /// Seconds
+        max_age: Option<i64>,
+        max_rows: Option<i64>,
+    }
+
+    impl RetentionPolicy {
+        pub fn new(max_days: Option<u64>, max_rows: Option<u64>) -> Self {
+            Self {
+                max_age: max_days.map(|days| days as i64 * 86400),
+                max_rows: max_rows.map(|rows| rows as i64),
+            }
+        }
+
+        pub fn is_enabled(&self) -> bool {
+            self.max_age.is_some() || self.max_rows.is_some()
+        }
+    }
+
+    /// Delete rows older than `max_age` or beyond newest `max_rows`. Rows not
+    /// older than `keep_since`, the oldest record termux still returns, are
+    /// always kept so they are never forwarded again.
+    pub async fn prune(
+        conn: &mut SqliteConnection,
+        table: &str,
+        policy: &RetentionPolicy,
+        keep_since: i64,
+        now: i64,
+    ) -> Result<u64> {
+        let mut deleted = 0;
+        if let Some(max_age) = policy.max_age {
+            deleted += sqlx::query(&format!(r#"DELETE FROM "{}" WHERE "timestamp" < ?"#, table))
+                .bind(keep_since.min(now - max_age))
+                .execute(&mut *conn)
+                .await?
+                .rows_affected();
+        }
+        if let Some(max_rows) = policy.max_rows {
+            deleted += sqlx::query(&format!(
+                r#"DELETE FROM "{table}" WHERE "timestamp" < ? AND "identifier" NOT IN
+                    (SELECT "identifier" FROM "{table}" ORDER BY "timestamp" DESC LIMIT ?)"#,
+                table = table
+            ))
+            .bind(keep_since)
+            .bind(max_rows)
+            .execute(&mut *conn)
+            .await?
+            .rows_affected();
+        }
+        Ok(deleted)
+    }
+
+    /// Return free pages to file system, database is converted to incremental
+    /// auto vacuum by a full `VACUUM` the first time.
+    pub async fn vacuum(conn: &mut SqliteConnection) -> Result<()> {
+        let (mode,): (i64,) = sqlx::query_as("PRAGMA auto_vacuum")
+            .fetch_one(&mut *conn)
+            .await?;
+        if mode != 2 {
+            log::info!("Convert database to incremental auto vacuum");
+            sqlx::query("PRAGMA auto_vacuum = INCREMENTAL")
+                .execute(&mut *conn)
+                .await?;
+            sqlx::query("VACUUM").execute(&mut *conn).await?;
+        } else {
+            sqlx::query("PRAGMA incremental_vacuum")
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Prune every table with a known `keep_since`, vacuum if anything deleted
+    pub async fn prune_all(
+        conn: &mut SqliteConnection,
+        policy: &RetentionPolicy,
+        keep_since: &[(&str, Option<i64>)],
+        now: i64,
+    ) -> Result<u64> {
+        let mut deleted = 0;
+        for (table, keep_since) in keep_since {
+            if let Some(keep_since) = keep_since {
+                deleted += prune(conn, table, policy, *keep_since, now).await?;
+            }
+        }
+        if deleted > 0 {
+            log::info!("Pruned {} seen identifiers", deleted);
+            vacuum(conn).await?;
+        }
+        Ok(deleted)
+    }
+}
+
+/// Every polled battery status is recorded, older rows are merged into
+/// buckets whose length is kept in `resolution` column.
+pub mod battery_history {
+    use anyhow::Result;
+    use sqlx::{sqlite::SqliteConnection, Connection};
+
+    use crate::datastructures::BatteryStatus;
+
+    /// Rows older than age (seconds) are merged into buckets of resolution
+    /// (seconds), rows older than [`MAX_AGE`] are deleted.
+    pub const DOWNSAMPLE_STEPS: &[(i64, i64)] = &[(3600, 60), (86400, 900)];
+    pub const MAX_AGE: i64 = 30 * 86400;
+
+    /// History read to estimate time to full or empty
+    pub const ESTIMATE_HISTORY: i64 = 7 * 86400;
+    /// Session used for estimation should last at least this many seconds
+    const MIN_SESSION: i64 = 600;
+    /// Longer estimate is considered nonsense
+    const MAX_ESTIMATE: i64 = 7 * 86400;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct BatteryRecord {
+        pub timestamp: i64,
+        pub percentage: i64,
+        pub status: String,
+        pub plugged: String,
+        pub temperature: f64,
+        pub current: i64,
+    }
+
+    pub async fn record(
+        conn: &mut SqliteConnection,
+        status: &BatteryStatus,
+        timestamp: i64,
+    ) -> Result<()> {
+        sqlx::query(r#"INSERT INTO "battery_history" VALUES (?, ?, ?, ?, ?, ?, 0)"#)
+            .bind(timestamp)
+            .bind(status.get_percentage())
+            .bind(status.get_status())
+            .bind(status.get_plugged())
+            .bind(status.get_temperature())
+            .bind(status.get_current())
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Merge rows of each step into averaged buckets, status and plugged are
+    /// taken from the latest row of bucket. Cutoff is aligned to bucket, so a
+    /// bucket is always merged at once.
+    pub async fn downsample(conn: &mut SqliteConnection, now: i64) -> Result<u64> {
+        let mut transaction = conn.begin().await?;
+        let mut merged = 0;
+        for (age, resolution) in DOWNSAMPLE_STEPS {
+            let cutoff = (now - age) / resolution * resolution;
+            sqlx::query(
+                r#"INSERT INTO "battery_history"
+                SELECT "bucket", "percentage", "status", "plugged", "temperature", "current", ?1
+                FROM (
+                    SELECT "timestamp" / ?1 * ?1 AS "bucket",
+                        CAST(ROUND(AVG("percentage")) AS INTEGER) AS "percentage",
+                        "status", "plugged",
+                        AVG("temperature") AS "temperature",
+                        CAST(ROUND(AVG("current")) AS INTEGER) AS "current",
+                        MAX("timestamp")
+                    FROM "battery_history"
+                    WHERE "timestamp" < ?2 AND "resolution" < ?1
+                    GROUP BY "bucket"
+                )"#,
+            )
+            .bind(resolution)
+            .bind(cutoff)
+            .execute(&mut transaction)
+            .await?;
+            merged += sqlx::query(
+                r#"DELETE FROM "battery_history" WHERE "timestamp" < ? AND "resolution" < ?"#,
+            )
+            .bind(cutoff)
+            .bind(resolution)
+            .execute(&mut transaction)
+            .await?
+            .rows_affected();
+        }
+        sqlx::query(r#"DELETE FROM "battery_history" WHERE "timestamp" < ?"#)
+            .bind(now - MAX_AGE)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(merged)
+    }
+
+    /// `Some(true)` while charging, `Some(false)` while discharging, `None`
+    /// if battery is full or status is unknown
+    fn direction(status: &str) -> Option<bool> {
+        match status.to_uppercase().as_str() {
+            "CHARGING" => Some(true),
+            "DISCHARGING" | "NOT_CHARGING" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Seconds until battery is full while charging or empty while
+    /// discharging. Percent per second is learned from the latest session of
+    /// same direction in `records`, then scaled by current now relative to
+    /// average current of that session, so the unit of current does not
+    /// matter. Zero current, current of other sign or too far from the session
+    /// is ignored and learned rate is used as is.
+    pub fn estimate_remaining(records: &[BatteryRecord], status: &BatteryStatus) -> Option<i64> {
+        let charging = direction(status.get_status())?;
+        let level = status.get_percentage() as i64;
+        let remaining = if charging { 100 - level } else { level };
+        if remaining <= 0 {
+            return None;
+        }
+        let (rate, session_current) = records
+            .split(|record| direction(&record.status) != Some(charging))
+            .rev()
+            .find_map(|session| {
+                let (first, last) = (session.first()?, session.last()?);
+                let span = last.timestamp - first.timestamp;
+                let gained = if charging {
+                    last.percentage - first.percentage
+                } else {
+                    first.percentage - last.percentage
+                };
+                if span < MIN_SESSION || gained < 1 {
+                    return None;
+                }
+                let current = session.iter().map(|record| record.current).sum::<i64>() as f64
+                    / session.len() as f64;
+                Some((gained as f64 / span as f64, current))
+            })?;
+        let ratio = status.get_current() as f64 / session_current;
+        let rate = if ratio.is_finite() && (0.1..=10.0).contains(&ratio) {
+            rate * ratio
+        } else {
+            rate
+        };
+        let seconds = (remaining as f64 / rate).round() as i64;
+        (seconds <= MAX_ESTIMATE).then_some(seconds)
+    }
+
+    /// e.g. `Time to full: about 1h 5m`, `None` if battery is full
+    pub fn format_estimate(status: &BatteryStatus, seconds: Option<i64>) -> Option<String> {
+        let charging = direction(status.get_status())?;
+        if charging && status.get_percentage() >= 100 {
+            return None;
+        }
+        let estimate = match seconds {
+            Some(seconds) if seconds >= 3600 => {
+                format!("about {}h {}m", seconds / 3600, seconds % 3600 / 60)
+            }
+            Some(seconds) => format!("about {}m", (seconds / 60).max(1)),
+            None => "unknown".to_string(),
+        };
+        Some(format!(
+            "Time to {}: {}",
+            if charging { "full" } else { "empty" },
+            estimate
+        ))
+    }
+
+    /// Records since `since`, oldest first
+    pub async fn fetch(conn: &mut SqliteConnection, since: i64) -> Result<Vec<BatteryRecord>> {
+        let rows: Vec<(i64, i64, String, String, f64, i64)> = sqlx::query_as(
+            r#"SELECT "timestamp", "percentage", "status", "plugged", "temperature", "current"
+            FROM "battery_history" WHERE "timestamp" >= ? ORDER BY "timestamp""#,
+        )
+        .bind(since)
+        .fetch_all(&mut *conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(timestamp, percentage, status, plugged, temperature, current)| BatteryRecord {
+                    timestamp,
+                    percentage,
+                    status,
+                    plugged,
+                    temperature,
+                    current,
+                },
+            )
+            .collect())
+    }
+}
+
+/// Application level encryption. A random data key is sealed by a key derived
+/// from passphrase, so `rekey` only seals it again. Identifiers are stored as
+/// keyed hashes and archived content is encrypted by XChaCha20-Poly1305, the
+/// full-text index is not kept for encrypted database.
+pub mod crypto {
+    use anyhow::{anyhow, Result};
+    use chacha20poly1305::aead::{Aead, NewAead};
+    use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+    use hmac::{Hmac, Mac, NewMac};
+    use rand::RngCore;
+    use sha2::Sha256;
+    use sqlx::{sqlite::SqliteConnection, Connection};
+
+    /// Passphrase is read from this environment variable before key file
+    pub const KEY_ENV: &str = "SMS_CLIENT_DATABASE_KEY";
+    /// New passphrase of `rekey` subcommand
+    pub const NEW_KEY_ENV: &str = "SMS_CLIENT_NEW_DATABASE_KEY";
+
+    /// `client_meta` keys of passphrase salt and sealed data key
+    const KEY_SALT: &str = "key_salt";
+    const SEALED_KEY: &str = "sealed_key";
+
+    const KDF_ROUNDS: u32 = 100_000;
+    const KEY_SIZE: usize = 32;
+    const SALT_SIZE: usize = 16;
+    const NONCE_SIZE: usize = 24;
+
+    /// Columns hold identifiers, replaced by keyed hash on encryption
+    const IDENTIFIER_COLUMNS: &[(&str, &str)] = &[
+        ("messages", "identifier"),
+        ("call_logs", "identifier"),
+        ("notifications", "identifier"),
+        ("notification_messages", "key"),
+    ];
+
+    #[derive(Clone)]
+    struct DataKey {
+        key: [u8; KEY_SIZE],
+        salt: String,
+        sealed_key: String,
+    }
+
+    impl DataKey {
+        fn seal(key: [u8; KEY_SIZE], passphrase: &str) -> Self {
+            let salt = random_bytes(SALT_SIZE);
+            let sealed_key = encrypt(&derive_key(passphrase, &salt), &key);
+            Self {
+                key,
+                salt: to_hex(&salt),
+                sealed_key,
+            }
+        }
+
+        fn open(salt: &str, sealed_key: &str, passphrase: &str) -> Result<Self> {
+            let key = from_hex(salt)
+                .and_then(|salt| decrypt(&derive_key(passphrase, &salt), sealed_key))
+                .filter(|key| key.len() == KEY_SIZE)
+                .ok_or_else(|| anyhow!("Wrong database key, unable to decrypt database"))?;
+            let mut data_key = [0; KEY_SIZE];
+            data_key.copy_from_slice(&key);
+            Ok(Self {
+                key: data_key,
+                salt: salt.to_string(),
+                sealed_key: sealed_key.to_string(),
+            })
+        }
+    }
+
+    /// Values pass through unchanged if database is not encrypted
+    #[derive(Clone, Default)]
+    pub struct Cipher(Option<DataKey>);
+
+    impl Cipher {
+        fn generate(passphrase: &str) -> Self {
+            let mut key = [0; KEY_SIZE];
+            rand::rngs::OsRng.fill_bytes(&mut key);
+            Self(Some(DataKey::seal(key, passphrase)))
+        }
+
+        pub fn is_enabled(&self) -> bool {
+            self.0.is_some()
+        }
+
+        /// Keyed hash, so stored identifier can not be checked against guessed
+        /// content
+        pub fn identifier(&self, identifier: &str) -> String {
+            match self.0 {
+                Some(ref data_key) => {
+                    let mut mac = Hmac::<Sha256>::new_from_slice(&data_key.key)
+                        .expect("HMAC accepts key of any size");
+                    mac.update(identifier.as_bytes());
+                    to_hex(&mac.finalize().into_bytes())
+                }
+                None => identifier.to_string(),
+            }
+        }
+
+        pub fn seal(&self, text: &str) -> String {
+            match self.0 {
+                Some(ref data_key) => encrypt(&data_key.key, text.as_bytes()),
+                None => text.to_string(),
+            }
+        }
+
+        pub fn open(&self, text: &str) -> Result<String> {
+            match self.0 {
+                Some(ref data_key) => Ok(String::from_utf8(
+                    decrypt(&data_key.key, text)
+                        .ok_or_else(|| anyhow!("Unable to decrypt archived content"))?,
+                )?),
+                None => Ok(text.to_string()),
+            }
+        }
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn from_hex(s: &str) -> Option<Vec<u8>> {
+        s.as_bytes()
+            .chunks(2)
+            .map(|pair| match pair {
+                [high, low] => {
+                    u8::from_str_radix(std::str::from_utf8(&[*high, *low]).ok()?, 16).ok()
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn random_bytes(size: usize) -> Vec<u8> {
+        let mut bytes = vec![0; size];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        bytes
+    }
+
+    fn derive_key(passphrase: &str, salt: &[u8]) -> [u8; KEY_SIZE] {
+        let mut key = [0; KEY_SIZE];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, KDF_ROUNDS, &mut key);
+        key
+    }
+
+    /// Random nonce followed by ciphertext
+    fn encrypt_bytes(key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = random_bytes(NONCE_SIZE);
+        let ciphertext = XChaCha20Poly1305::new(Key::from_slice(key))
+            .encrypt(XNonce::from_slice(&nonce), plaintext)
+            .expect("Plaintext is too long to encrypt");
+        [nonce, ciphertext].concat()
+    }
+
+    fn decrypt_bytes(key: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        XChaCha20Poly1305::new(Key::from_slice(key))
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .ok()
+    }
+
+    fn encrypt(key: &[u8], plaintext: &[u8]) -> String {
+        to_hex(&encrypt_bytes(key, plaintext))
+    }
+
+    fn decrypt(key: &[u8], sealed: &str) -> Option<Vec<u8>> {
+        decrypt_bytes(key, &from_hex(sealed)?)
+    }
+
+    /// Salt followed by sealed `data`, key is derived from `passphrase`
+    pub fn seal_with_passphrase(passphrase: &str, data: &[u8]) -> Vec<u8> {
+        let salt = random_bytes(SALT_SIZE);
+        let sealed = encrypt_bytes(&derive_key(passphrase, &salt), data);
+        [salt, sealed].concat()
+    }
+
+    pub fn open_with_passphrase(passphrase: &str, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < SALT_SIZE {
+            return None;
+        }
+        let (salt, sealed) = sealed.split_at(SALT_SIZE);
+        decrypt_bytes(&derive_key(passphrase, salt), sealed)
+    }
+
+    /// Passphrase from environment variable `env`, or content of `key_file`
+    /// without trailing newline
+    pub async fn read_passphrase(env: &str, key_file: Option<&str>) -> Result<Option<String>> {
+        let passphrase = match std::env::var(env) {
+            Ok(passphrase) => passphrase,
+            Err(_) => match key_file {
+                Some(path) => tokio::fs::read_to_string(path)
+                    .await
+                    .map_err(|e| anyhow!("Unable to read database key file {:?}: {}", path, e))?
+                    .trim_end_matches(&['\r', '\n'][..])
+                    .to_string(),
+                None => return Ok(None),
+            },
+        };
+        if passphrase.is_empty() {
+            return Err(anyhow!("Database key is empty"));
+        }
+        Ok(Some(passphrase))
+    }
+
+    /// Salt and sealed data key, `None` if database is not encrypted
+    async fn load_key(conn: &mut SqliteConnection) -> Result<Option<(String, String)>> {
+        if super::migration::get_version(conn).await?.is_none() {
+            return Ok(None);
+        }
+        let rows: Vec<(String, String)> =
+            sqlx::query_as(r#"SELECT "key", "value" FROM "client_meta" WHERE "key" IN (?, ?)"#)
+                .bind(KEY_SALT)
+                .bind(SEALED_KEY)
+                .fetch_all(&mut *conn)
+                .await?;
+        let get = |key: &str| {
+            rows.iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.clone())
+        };
+        Ok(get(KEY_SALT).zip(get(SEALED_KEY)))
+    }
+
+    /// Decrypt data key of database, a new key is generated if database is
+    /// not created yet, it is stored by [`save_key`] after tables are created.
+    pub async fn unlock(conn: &mut SqliteConnection, passphrase: Option<&str>) -> Result<Cipher> {
+        match (load_key(conn).await?, passphrase) {
+            (Some((salt, sealed_key)), Some(passphrase)) => {
+                Ok(Cipher(Some(DataKey::open(&salt, &sealed_key, passphrase)?)))
+            }
+            (Some(_), None) => Err(anyhow!(
+                "Database is encrypted, set {} or database_key_file",
+                KEY_ENV
+            )),
+            (None, Some(passphrase)) => {
+                if super::migration::get_version(conn).await?.is_some() {
+                    return Err(anyhow!(
+                        "Database is not encrypted, encrypt it by `rekey` subcommand first"
+                    ));
+                }
+                Ok(Cipher::generate(passphrase))
+            }
+            (None, None) => Ok(Cipher::default()),
+        }
+    }
+
+    pub async fn save_key(conn: &mut SqliteConnection, cipher: &Cipher) -> Result<()> {
+        if let Some(ref data_key) = cipher.0 {
+            for (key, value) in &[
+                (KEY_SALT, &data_key.salt),
+                (SEALED_KEY, &data_key.sealed_key),
+            ] {
+                sqlx::query(r#"INSERT OR REPLACE INTO "client_meta" VALUES (?, ?)"#)
+                    .bind(key)
+                    .bind(value)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace identifiers by keyed hashes and encrypt archived content
+    async fn encrypt_tables(conn: &mut SqliteConnection, cipher: &Cipher) -> Result<()> {
+        for (table, column) in IDENTIFIER_COLUMNS {
+            let identifiers: Vec<(String,)> =
+                sqlx::query_as(&format!(r#"SELECT "{}" FROM "{}""#, column, table))
+                    .fetch_all(&mut *conn)
+                    .await?;
+            for (identifier,) in identifiers {
+                sqlx::query(&format!(
+                    r#"UPDATE "{table}" SET "{column}" = ? WHERE "{column}" = ?"#,
+                    table = table,
+                    column = column
+                ))
+                .bind(cipher.identifier(&identifier))
+                .bind(&identifier)
+                .execute(&mut *conn)
+                .await?;
+            }
+        }
+
+        let messages: Vec<(String, String, Option<String>, String)> = sqlx::query_as(
+            r#"SELECT "identifier", "number", "contact_name", "body" FROM "message_archive""#,
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        for (identifier, number, contact_name, body) in messages {
+            sqlx::query(
+                r#"UPDATE "message_archive" SET "identifier" = ?, "number" = ?, "contact_name" = ?, "body" = ? WHERE "identifier" = ?"#,
+            )
+            .bind(cipher.identifier(&identifier))
+            .bind(cipher.seal(&number))
+            .bind(contact_name.map(|name| cipher.seal(&name)))
+            .bind(cipher.seal(&body))
+            .bind(&identifier)
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        let call_logs: Vec<(String, String, String)> = sqlx::query_as(
+            r#"SELECT "identifier", "number", "contact_name" FROM "call_log_archive""#,
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        for (identifier, number, contact_name) in call_logs {
+            sqlx::query(
+                r#"UPDATE "call_log_archive" SET "identifier" = ?, "number" = ?, "contact_name" = ? WHERE "identifier" = ?"#,
+            )
+            .bind(cipher.identifier(&identifier))
+            .bind(cipher.seal(&number))
+            .bind(cipher.seal(&contact_name))
+            .bind(&identifier)
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        sqlx::query(r#"DELETE FROM "message_search""#)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Seal data key by new passphrase. Database not encrypted yet is
+    /// encrypted in place, then vacuumed so plaintext is not left in free
+    /// pages.
+    pub async fn rekey(
+        conn: &mut SqliteConnection,
+        passphrase: Option<&str>,
+        new_passphrase: &str,
+    ) -> Result<()> {
+        if super::migration::get_version(conn).await?.is_none() {
+            return Err(anyhow!("Database is not created yet"));
+        }
+        let mut transaction = conn.begin().await?;
+        let (cipher, encrypted) = match load_key(&mut transaction).await? {
+            Some((salt, sealed_key)) => {
+                let passphrase = passphrase.ok_or_else(|| {
+                    anyhow!(
+                        "Database is encrypted, set {} or database_key_file",
+                        KEY_ENV
+                    )
+                })?;
+                let data_key = DataKey::open(&salt, &sealed_key, passphrase)?;
+                (
+                    Cipher(Some(DataKey::seal(data_key.key, new_passphrase))),
+                    false,
+                )
+            }
+            None => {
+                let cipher = Cipher::generate(new_passphrase);
+                encrypt_tables(&mut transaction, &cipher).await?;
+                (cipher, true)
+            }
+        };
+        save_key(&mut transaction, &cipher).await?;
+        transaction.commit().await?;
+        if encrypted {
+            sqlx::query("VACUUM").execute(&mut *conn).await?;
+        }
+        Ok(())
+    }
 }
 
-pub use v1 as current;
-pub use v1::VERSION as CURRENT_VERSION;
+/// Consistent snapshot of live database, optionally gzip compressed and then
+/// encrypted by passphrase. Encrypted snapshot starts with [`MAGIC`].
+pub mod backup {
+    use std::ffi::CStr;
+    use std::io::{Read, Write};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use anyhow::{anyhow, Result};
+    use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+    use libsqlite3_sys as ffi;
+    use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqliteJournalMode};
+    use sqlx::{ConnectOptions, Connection};
+
+    use super::crypto::{open_with_passphrase, seal_with_passphrase};
+
+    /// Passphrase of encrypted snapshot is read from this environment
+    /// variable before key file
+    pub const KEY_ENV: &str = "SMS_CLIENT_BACKUP_KEY";
+
+    pub const MAGIC: &[u8] = b"TSCBAK1\0";
+    const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+    const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+    /// Times and interval in milliseconds of retrying locked source
+    const BUSY_RETRIES: u32 = 50;
+    const BUSY_SLEEP_MS: i32 = 100;
+
+    fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+        let mut path = path.as_os_str().to_owned();
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    /// Copy `main` of `source` to in-memory database by online backup API,
+    /// and return its file image, so plain snapshot is never written to disk.
+    ///
+    /// # Safety
+    ///
+    /// `source` should be an open connection not used by others meanwhile.
+    unsafe fn serialize(source: *mut ffi::sqlite3) -> Result<Vec<u8>> {
+        unsafe fn error(db: *mut ffi::sqlite3) -> anyhow::Error {
+            anyhow!(
+                "Unable to back up database: {}",
+                CStr::from_ptr(ffi::sqlite3_errmsg(db)).to_string_lossy()
+            )
+        }
+
+        let main = b"main\0".as_ptr().cast();
+        let mut memory = std::ptr::null_mut();
+        let code = ffi::sqlite3_open_v2(
+            b":memory:\0".as_ptr().cast(),
+            &mut memory,
+            ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+            std::ptr::null(),
+        );
+        let result = (|| {
+            if code != ffi::SQLITE_OK {
+                return Err(error(memory));
+            }
+            let backup = ffi::sqlite3_backup_init(memory, main, source, main);
+            if backup.is_null() {
+                return Err(error(memory));
+            }
+            // All pages are copied in one step, so snapshot is consistent
+            // while client is writing. Source locked by others is retried
+            // shortly before giving up, instead of keeping a partial copy.
+            let mut step = ffi::sqlite3_backup_step(backup, -1);
+            let mut retries = 0;
+            while (step == ffi::SQLITE_BUSY || step == ffi::SQLITE_LOCKED) && retries < BUSY_RETRIES
+            {
+                ffi::sqlite3_sleep(BUSY_SLEEP_MS);
+                retries += 1;
+                step = ffi::sqlite3_backup_step(backup, -1);
+            }
+            let finish = ffi::sqlite3_backup_finish(backup);
+            if step != ffi::SQLITE_DONE {
+                return Err(anyhow!(
+                    "Unable to back up database: {}",
+                    CStr::from_ptr(ffi::sqlite3_errstr(step)).to_string_lossy()
+                ));
+            }
+            if finish != ffi::SQLITE_OK {
+                return Err(error(memory));
+            }
+            let mut size = 0;
+            let image = ffi::sqlite3_serialize(memory, main, &mut size, 0);
+            if image.is_null() {
+                return Err(error(memory));
+            }
+            let data = std::slice::from_raw_parts(image, size as usize).to_vec();
+            ffi::sqlite3_free(image.cast());
+            Ok(data)
+        })();
+        ffi::sqlite3_close(memory);
+        result
+    }
+
+    pub async fn backup(
+        conn: &mut SqliteConnection,
+        output: &Path,
+        compress: bool,
+        passphrase: Option<&str>,
+    ) -> Result<()> {
+        if output.exists() {
+            return Err(anyhow!("{} already exists", output.display()));
+        }
+        let mut data = {
+            let mut handle = conn.lock_handle().await?;
+            // Worker of connection is waiting until handle is dropped
+            unsafe { serialize(handle.as_raw_handle().as_ptr()) }?
+        };
+        if compress {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data)?;
+            data = encoder.finish()?;
+        }
+        if let Some(passphrase) = passphrase {
+            data = [MAGIC, &seal_with_passphrase(passphrase, &data)].concat();
+        }
+        tokio::fs::write(output, data).await?;
+        Ok(())
+    }
+
+    /// Decrypt and decompress snapshot to plain SQLite database
+    fn unwrap_snapshot(mut data: Vec<u8>, passphrase: Option<&str>) -> Result<Vec<u8>> {
+        if data.starts_with(MAGIC) {
+            let passphrase = passphrase
+                .ok_or_else(|| anyhow!("Snapshot is encrypted, set {} or --key-file", KEY_ENV))?;
+            data = open_with_passphrase(passphrase, &data[MAGIC.len()..])
+                .ok_or_else(|| anyhow!("Wrong backup key, unable to decrypt snapshot"))?;
+        }
+        if data.starts_with(GZIP_MAGIC) {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?;
+            data = decompressed;
+        }
+        if !data.starts_with(SQLITE_MAGIC) {
+            return Err(anyhow!("Snapshot is not a SQLite database"));
+        }
+        Ok(data)
+    }
+
+    /// Snapshot should pass integrity check and its schema should not be newer
+    /// than [`super::CURRENT_VERSION`], older one is migrated on next start.
+    async fn validate(path: &Path) -> Result<()> {
+        let mut conn = SqliteConnectOptions::new().filename(path).connect().await?;
+        let (integrity,): (String,) = sqlx::query_as("PRAGMA integrity_check")
+            .fetch_one(&mut conn)
+            .await?;
+        if integrity != "ok" {
+            return Err(anyhow!("Snapshot is corrupted: {}", integrity));
+        }
+        let current: u32 = super::CURRENT_VERSION.parse()?;
+        let result = match super::migration::get_version(&mut conn).await? {
+            None => Err(anyhow!("Snapshot is not a client database")),
+            Some(version) if version > current => Err(anyhow!(
+                "Snapshot version {} is newer than supported version {}, please upgrade client",
+                version,
+                current
+            )),
+            Some(_) => Ok(()),
+        };
+        conn.close().await?;
+        result
+    }
+
+    /// Replace database by snapshot, refused if database is opened by client.
+    /// Replaced database is kept as `<database>.<timestamp>.replaced.bak`.
+    ///
+    /// Running client is detected by exclusive lock, which relies on WAL mode
+    /// of database: every open WAL connection keeps a shared lock even when
+    /// idle, while in rollback journal mode only one in a transaction does.
+    pub async fn restore(database: &Path, input: &Path, passphrase: Option<&str>) -> Result<()> {
+        let data = unwrap_snapshot(tokio::fs::read(input).await?, passphrase)?;
+        let restored = with_suffix(database, ".restore");
+        tokio::fs::write(&restored, data).await?;
+        if let Err(e) = validate(&restored).await {
+            tokio::fs::remove_file(&restored).await?;
+            return Err(e);
+        }
+        if !database.exists() {
+            tokio::fs::rename(&restored, database).await?;
+            return Ok(());
+        }
+        let result = replace(database, &restored).await;
+        if result.is_err() && restored.exists() {
+            tokio::fs::remove_file(&restored).await?;
+        }
+        result
+    }
+
+    /// Move database aside while holding exclusive lock, so client can not
+    /// open it meanwhile
+    async fn replace(database: &Path, restored: &Path) -> Result<()> {
+        let mut conn = SqliteConnectOptions::new()
+            .filename(database)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(Duration::from_secs(0))
+            .connect()
+            .await?;
+        // Switching to WAL is silently ignored if it is unsupported
+        let (journal_mode,): (String,) = sqlx::query_as("PRAGMA journal_mode")
+            .fetch_one(&mut conn)
+            .await?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            return Err(anyhow!(
+                "{} is in {} journal mode instead of WAL, unable to tell if client is running",
+                database.display(),
+                journal_mode
+            ));
+        }
+        sqlx::query("PRAGMA locking_mode = EXCLUSIVE")
+            .execute(&mut conn)
+            .await?;
+        if sqlx::query("BEGIN EXCLUSIVE")
+            .execute(&mut conn)
+            .await
+            .is_err()
+        {
+            return Err(anyhow!(
+                "{} is in use, stop client before restore",
+                database.display()
+            ));
+        }
+        sqlx::query("COMMIT").execute(&mut conn).await?;
+        // Write back WAL and remove it, so it is not applied to restored
+        // database
+        sqlx::query("PRAGMA journal_mode = DELETE")
+            .execute(&mut conn)
+            .await?;
+        let replaced = with_suffix(
+            database,
+            &format!(".{}.replaced.bak", chrono::Utc::now().timestamp()),
+        );
+        log::info!("Keep replaced database as {}", replaced.display());
+        tokio::fs::rename(database, replaced).await?;
+        tokio::fs::rename(restored, database).await?;
+        conn.close().await?;
+        Ok(())
+    }
+}
@@ -18,67 +18,324 @@
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::{error::Error, fmt::Display};
+use std::{collections::HashMap, convert::TryFrom, error::Error, fmt::Display};
 
 use anyhow::Result;
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
 use serde::Deserialize;
 use sha2::{digest::DynDigest, Digest, Sha256};
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Configure {
+    /// Telegram bot api server, e.g. `https://api.telegram.org`
     upstream: String,
-    applications: Option<Vec<String>>,
+    bot_token: String,
+    /// Chat id which receive forwarded messages and allowed to send commands
+    owner: i64,
+    /// IANA timezone name of the phone, detected if not set
+    timezone: Option<String>,
+    /// Applications whose notifications should be forwarded, either a package
+    /// name or a table with filter rules
+    applications: Option<Vec<notification::RawApplicationRule>>,
+    /// Seconds each termux-api command may take, default depends on command
+    termux_timeout: Option<u64>,
+    /// Alert after this many calls of one termux-api command timed out in a row
+    unresponsive_threshold: Option<u32>,
+    /// Boxes of forwarded SMS, add `sent` to forward messages sent from the
+    /// phone, default `["inbox"]`
+    sms_boxes: Option<Vec<String>>,
+    /// Keep content of every SMS and call log in database
+    archive: Option<bool>,
+    /// Forget seen identifiers older than this many days
+    retention_days: Option<u64>,
+    /// Keep at most this many seen identifiers of each kind
+    retention_max_rows: Option<u64>,
+    /// File contains passphrase of encrypted database, environment variable
+    /// `SMS_CLIENT_DATABASE_KEY` is preferred
+    database_key_file: Option<String>,
+    /// Database file, relative path is resolved from directory of this file
+    database: Option<String>,
+    /// Alert once when battery level drops to each of these percentages
+    battery_discharge_thresholds: Option<Vec<i8>>,
+    /// Alert once when battery is charged to each of these percentages
+    battery_charge_thresholds: Option<Vec<i8>>,
+    /// Percent level must move back past a threshold before it alerts again
+    battery_hysteresis: Option<i8>,
+    /// Alert when battery temperature reaches this many degrees Celsius
+    battery_temperature_warning: Option<f32>,
+    battery_temperature_critical: Option<f32>,
+    /// Repeat temperature alert of same level at most once in this duration,
+    /// e.g. `30m`
+    battery_temperature_cooldown: Option<String>,
 }
 
 impl Configure {
+    pub async fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Unable to read config {}: {}", path.display(), e))?;
+        Ok(toml::from_str(&content)?)
+    }
+
     pub fn get_upstream(&self) -> &String {
         &self.upstream
     }
+
+    pub fn get_bot_token(&self) -> &String {
+        &self.bot_token
+    }
+
+    pub fn get_owner(&self) -> i64 {
+        self.owner
+    }
+
+    pub fn get_timezone(&self) -> Option<&String> {
+        self.timezone.as_ref()
+    }
+
+    pub fn get_applications(&self) -> Result<Vec<notification::ApplicationRule>> {
+        self.applications
+            .iter()
+            .flatten()
+            .map(notification::ApplicationRule::try_from)
+            .collect()
+    }
+
+    pub fn get_termux_timeout(&self) -> Option<std::time::Duration> {
+        self.termux_timeout.map(std::time::Duration::from_secs)
+    }
+
+    pub fn get_unresponsive_threshold(&self) -> u32 {
+        self.unresponsive_threshold.unwrap_or(3)
+    }
+
+    pub fn get_retention_days(&self) -> Option<u64> {
+        self.retention_days
+    }
+
+    pub fn get_retention_max_rows(&self) -> Option<u64> {
+        self.retention_max_rows
+    }
+
+    pub fn get_database(&self) -> Option<&String> {
+        self.database.as_ref()
+    }
+
+    pub fn get_database_key_file(&self) -> Option<&String> {
+        self.database_key_file.as_ref()
+    }
+
+    pub fn is_archive_enabled(&self) -> bool {
+        self.archive.unwrap_or(false)
+    }
+
+    pub fn get_battery_policy(&self) -> Result<battery::BatteryPolicy> {
+        battery::BatteryPolicy::new(
+            self.battery_discharge_thresholds.clone(),
+            self.battery_charge_thresholds.clone(),
+            self.battery_hysteresis,
+        )?
+        .with_temperature(
+            self.battery_temperature_warning,
+            self.battery_temperature_critical,
+            self.battery_temperature_cooldown
+                .as_deref()
+                .map(parse_duration)
+                .transpose()?,
+        )
+    }
+
+    pub fn get_sms_boxes(&self) -> Result<Vec<sms::MessageBox>> {
+        match self.sms_boxes {
+            Some(ref boxes) => Ok(boxes
+                .iter()
+                .map(|s| sms::MessageBox::try_from(s.as_str()))
+                .collect::<Result<_, _>>()?),
+            None => Ok(vec![sms::MessageBox::Inbox]),
+        }
+    }
 }
 
 pub trait Identifier {
+    /// UTC timestamp
     fn get_timestamp(&self) -> i64;
 
+    /// Local wall-clock time read as UTC, identifiers were always generated
+    /// from this value so records seen before keep being recognized.
+    fn get_local_timestamp(&self) -> i64 {
+        self.get_timestamp()
+    }
+
     fn get_body(&self) -> String;
 
+    /// Fields which tell apart different records in the same second, used by
+    /// identifier v2
+    fn get_unique_fields(&self) -> String {
+        self.get_body()
+    }
+
+    /// Identifier v1, records with same second and body share same identifier
     fn get_identifier(&self) -> String {
         let mut sha256 = Sha256::new();
-        let s = format!("{}{}", self.get_timestamp(), self.get_body());
+        let s = format!("{}{}", self.get_local_timestamp(), self.get_body());
         let bytes = s.as_bytes();
-        DynDigest::update(&mut sha256, &bytes);
+        DynDigest::update(&mut sha256, bytes);
+        let result = sha256.finalize();
+        format!("{:x}", result)
+    }
+
+    /// Identifier v2, `occurrence` counts exact duplicates listed before this
+    /// one, see [`get_identifiers`]
+    fn get_identifier_v2(&self, occurrence: usize) -> String {
+        let mut sha256 = Sha256::new();
+        let s = format!(
+            "v2\0{}\0{}\0{}",
+            self.get_timestamp(),
+            self.get_unique_fields(),
+            occurrence
+        );
+        DynDigest::update(&mut sha256, s.as_bytes());
         let result = sha256.finalize();
         format!("{:x}", result)
     }
 }
 
-pub fn convert_string_to_timestamp(s: &str) -> Result<i64> {
-    Ok(NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")?.timestamp())
+/// Identifier v2 of every record, exact duplicates are numbered in listed order.
+pub fn get_identifiers<T: Identifier>(records: &[T]) -> Vec<String> {
+    let mut occurrences: HashMap<(i64, String), usize> = HashMap::new();
+    records
+        .iter()
+        .map(|record| {
+            let occurrence = occurrences
+                .entry((record.get_timestamp(), record.get_unique_fields()))
+                .or_insert(0);
+            let identifier = record.get_identifier_v2(*occurrence);
+            *occurrence += 1;
+            identifier
+        })
+        .collect()
+}
+
+/// SIM slot reported by recent termux-api, either a number or a string
+fn format_sim_id(sim_id: &Option<serde_json::Value>) -> Option<String> {
+    sim_id.as_ref().map(|value| match value {
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    })
+}
+
+/// Termux print time in phone's local time without offset, return
+/// (UTC timestamp, local wall-clock timestamp).
+///
+/// Ambiguous time during DST fall back is resolved to the earlier instant,
+/// time skipped by DST spring forward is moved forward one hour.
+pub fn convert_string_to_timestamp(
+    s: &str,
+    timezone: &Tz,
+) -> std::result::Result<(i64, i64), ParseError> {
+    let datetime = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .map_err(|_| ParseError::Timestamp(s.to_string()))?;
+    let local = timezone
+        .from_local_datetime(&datetime)
+        .earliest()
+        .or_else(|| {
+            timezone
+                .from_local_datetime(&(datetime + Duration::hours(1)))
+                .earliest()
+        })
+        .ok_or_else(|| ParseError::Timestamp(s.to_string()))?;
+    Ok((local.timestamp(), datetime.and_utc().timestamp()))
+}
+
+/// Falls back to the raw timestamp if it is out of range
+pub fn format_timestamp(timestamp: i64, timezone: &Tz) -> String {
+    timezone
+        .timestamp_opt(timestamp, 0)
+        .single()
+        .map(|datetime| datetime.format("%Y-%m-%d %H:%M:%S %Z").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
 }
 
+/// Number followed by unit `s`, `m`, `h` or `d`, e.g. `12h`
+pub fn parse_duration(s: &str) -> Result<std::time::Duration> {
+    let unit = match s.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 3600,
+        Some('d') => 86400,
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Duration {:?} should end with s, m, h or d",
+                s
+            ))
+        }
+    };
+    let value: u64 = s[..s.len() - 1]
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Unable to parse duration {:?}", s))?;
+    // Callers subtract it from a timestamp, so it should also fit in `i64`
+    let seconds = value
+        .checked_mul(unit)
+        .filter(|seconds| i64::try_from(*seconds).is_ok())
+        .ok_or_else(|| anyhow::anyhow!("Duration {:?} is too large", s))?;
+    Ok(std::time::Duration::from_secs(seconds))
+}
+
+/// Record from termux which can not be understood, it should be skipped instead
+/// of stopping the whole client.
 #[derive(Debug, Clone)]
-pub struct PermissionError {}
+pub enum ParseError {
+    Timestamp(String),
+    CallLogType(String),
+    MessageBox(String),
+    Json(String),
+}
 
-impl Error for PermissionError {}
+impl Error for ParseError {}
 
-impl Display for PermissionError {
+impl Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Permission denied, please give specify permission to termux:api app"
-        )
+        match self {
+            ParseError::Timestamp(s) => write!(f, "Unable to parse timestamp: {:?}", s),
+            ParseError::CallLogType(s) => write!(f, "Unknown call log type: {:?}", s),
+            ParseError::MessageBox(s) => write!(f, "Unknown message box: {:?}", s),
+            ParseError::Json(s) => write!(f, "Unable to parse json: {}", s),
+        }
     }
 }
 
-impl PermissionError {
-    pub fn new() -> Self {
-        Self {}
+impl From<serde_json::Error> for ParseError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e.to_string())
     }
 }
 
+/// Convert every raw record, bad records are logged and skipped.
+pub fn convert_records<'a, R, T>(records: &'a [R], timezone: &'a Tz) -> Vec<T>
+where
+    R: std::fmt::Debug,
+    T: TryFrom<(&'a R, &'a Tz), Error = ParseError>,
+{
+    records
+        .iter()
+        .filter_map(|record| match T::try_from((record, timezone)) {
+            Ok(element) => Some(element),
+            Err(e) => {
+                log::warn!("Skip bad record {:?}: {}", record, e);
+                None
+            }
+        })
+        .collect()
+}
+
 pub mod battery {
 
-    use anyhow::Result;
+    use std::convert::TryFrom;
+    use std::time::{Duration, Instant};
+
+    use super::ParseError;
     use serde::Deserialize;
 
     #[allow(dead_code)]
@@ -99,12 +356,15 @@ pub mod battery {
             let status = self.get_changer_status();
             write!(
                 f,
-                "{}\nCurrent battery level: {}",
+                "{}\nPower source: {}\nCurrent battery level: {}\nTemperature: {:.1}°C, health: {}",
                 match status {
                     BatteryChangerStatus::Charging => "Changer is connected.",
                     BatteryChangerStatus::Discharging => "Changer is disconnect.",
                 },
-                self.get_percentage()
+                self.get_power_source(),
+                self.get_percentage(),
+                self.get_temperature(),
+                self.get_health()
             )
         }
     }
@@ -117,15 +377,75 @@ pub mod battery {
         Discharging,
     }
 
-    impl BatteryStatus {
-        pub fn from_str(s: &str) -> Result<Self> {
-            Ok(serde_json::from_str(s)?)
+    /// Power source reported in `plugged`
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum PowerSource {
+        Unplugged,
+        Ac,
+        Usb,
+        Wireless,
+        /// Source not known by this client, e.g. `PLUGGED_DOCK`
+        Other(String),
+    }
+
+    impl From<&str> for PowerSource {
+        fn from(s: &str) -> Self {
+            match s.to_uppercase().as_str() {
+                "UNPLUGGED" => Self::Unplugged,
+                "PLUGGED_AC" => Self::Ac,
+                "PLUGGED_USB" => Self::Usb,
+                "PLUGGED_WIRELESS" => Self::Wireless,
+                _ => Self::Other(s.to_string()),
+            }
         }
+    }
 
+    impl std::fmt::Display for PowerSource {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                PowerSource::Unplugged => write!(f, "unplugged"),
+                PowerSource::Ac => write!(f, "AC"),
+                PowerSource::Usb => write!(f, "USB"),
+                PowerSource::Wireless => write!(f, "wireless"),
+                PowerSource::Other(s) => write!(f, "{}", s),
+            }
+        }
+    }
+
+    impl BatteryStatus {
         pub fn get_percentage(&self) -> i8 {
             self.percentage
         }
 
+        /// Degrees Celsius
+        pub fn get_temperature(&self) -> f32 {
+            self.temperature
+        }
+
+        /// e.g. `GOOD`, `OVERHEAT`, `DEAD` or `OVER_VOLTAGE`
+        pub fn get_health(&self) -> &str {
+            &self.health
+        }
+
+        /// e.g. `CHARGING`, `DISCHARGING`, `FULL` or `NOT_CHARGING`
+        pub fn get_status(&self) -> &str {
+            &self.status
+        }
+
+        /// e.g. `UNPLUGGED`, `PLUGGED_AC`, `PLUGGED_USB` or `PLUGGED_WIRELESS`
+        pub fn get_plugged(&self) -> &str {
+            &self.plugged
+        }
+
+        pub fn get_power_source(&self) -> PowerSource {
+            PowerSource::from(self.plugged.as_str())
+        }
+
+        /// Microampere, positive while charging on most devices
+        pub fn get_current(&self) -> i32 {
+            self.current
+        }
+
         pub fn get_changer_status(&self) -> BatteryChangerStatus {
             if self.status.to_lowercase().eq("charging") {
                 BatteryChangerStatus::Charging
@@ -139,26 +459,34 @@ pub mod battery {
         }
     }
 
-    impl From<&str> for BatteryStatus {
-        fn from(s: &str) -> Self {
-            serde_json::from_str(s).unwrap()
+    impl TryFrom<&str> for BatteryStatus {
+        type Error = ParseError;
+
+        fn try_from(s: &str) -> Result<Self, Self::Error> {
+            Ok(serde_json::from_str(s)?)
         }
     }
 
     #[derive(Debug, Clone)]
     pub struct CurrentStatus {
         charge_status: BatteryChangerStatus,
+        power_source: PowerSource,
         battery_level: i8,
     }
 
     impl CurrentStatus {
         pub fn update_charge_status(&mut self, status: &BatteryStatus) {
             self.charge_status = status.get_changer_status();
+            self.power_source = status.get_power_source();
             self.battery_level = status.get_percentage();
         }
 
+        /// Power source change is reported before charge status, they usually
+        /// change together
         pub fn not_equal(&self, status: &BatteryStatus) -> StatusDiff {
-            if self.charge_status != status.get_changer_status() {
+            if self.power_source != status.get_power_source() {
+                return StatusDiff::PowerSource;
+            } else if self.charge_status != status.get_changer_status() {
                 return StatusDiff::ChargeStatus;
             } else if self.battery_level != status.get_percentage() {
                 return StatusDiff::Battery;
@@ -166,26 +494,259 @@ pub mod battery {
             StatusDiff::Equal
         }
 
+        #[allow(dead_code)]
         pub fn get_battery_level(&self) -> i8 {
             self.battery_level
         }
 
+        #[allow(dead_code)]
         pub fn get_changer_status(&self) -> BatteryChangerStatus {
             self.charge_status.clone()
         }
+
+        pub fn get_power_source(&self) -> &PowerSource {
+            &self.power_source
+        }
     }
 
     impl From<&BatteryStatus> for CurrentStatus {
         fn from(bs: &BatteryStatus) -> Self {
             Self {
                 charge_status: bs.get_changer_status(),
+                power_source: bs.get_power_source(),
                 battery_level: bs.get_percentage(),
             }
         }
     }
 
+    /// Battery levels which are alerted once when crossed
+    #[derive(Debug, Clone)]
+    pub struct BatteryPolicy {
+        discharge_thresholds: Vec<i8>,
+        charge_thresholds: Vec<i8>,
+        hysteresis: i8,
+        temperature_warning: f32,
+        temperature_critical: f32,
+        temperature_cooldown: Duration,
+    }
+
+    impl BatteryPolicy {
+        pub fn new(
+            discharge_thresholds: Option<Vec<i8>>,
+            charge_thresholds: Option<Vec<i8>>,
+            hysteresis: Option<i8>,
+        ) -> anyhow::Result<Self> {
+            let default = Self::default();
+            let policy = Self {
+                discharge_thresholds: discharge_thresholds.unwrap_or(default.discharge_thresholds),
+                charge_thresholds: charge_thresholds.unwrap_or(default.charge_thresholds),
+                hysteresis: hysteresis.unwrap_or(default.hysteresis),
+                ..default
+            };
+            if let Some(threshold) = policy
+                .discharge_thresholds
+                .iter()
+                .chain(policy.charge_thresholds.iter())
+                .find(|threshold| !(0..=100).contains(*threshold))
+            {
+                return Err(anyhow::anyhow!(
+                    "Battery threshold {} is out of range 0-100",
+                    threshold
+                ));
+            }
+            if !(0..=20).contains(&policy.hysteresis) {
+                return Err(anyhow::anyhow!(
+                    "Battery hysteresis {} is out of range 0-20",
+                    policy.hysteresis
+                ));
+            }
+            Ok(policy)
+        }
+
+        pub fn with_temperature(
+            self,
+            warning: Option<f32>,
+            critical: Option<f32>,
+            cooldown: Option<Duration>,
+        ) -> anyhow::Result<Self> {
+            let policy = Self {
+                temperature_warning: warning.unwrap_or(self.temperature_warning),
+                temperature_critical: critical.unwrap_or(self.temperature_critical),
+                temperature_cooldown: cooldown.unwrap_or(self.temperature_cooldown),
+                ..self
+            };
+            if policy.temperature_warning >= policy.temperature_critical {
+                return Err(anyhow::anyhow!(
+                    "Battery warning temperature {} should be lower than critical temperature {}",
+                    policy.temperature_warning,
+                    policy.temperature_critical
+                ));
+            }
+            Ok(policy)
+        }
+    }
+
+    impl Default for BatteryPolicy {
+        fn default() -> Self {
+            Self {
+                discharge_thresholds: vec![50, 20, 10, 5],
+                charge_thresholds: vec![80, 100],
+                hysteresis: 2,
+                temperature_warning: 45.0,
+                temperature_critical: 55.0,
+                temperature_cooldown: Duration::from_secs(30 * 60),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+    pub enum TemperatureLevel {
+        Normal,
+        Warning,
+        Critical,
+    }
+
+    /// Threshold is armed while battery level is on its safe side, and fires
+    /// when level reaches it. Fired threshold is armed again only after level
+    /// moves back beyond it by `hysteresis`, so a level bouncing around it
+    /// alerts once.
+    #[derive(Debug, Clone)]
+    pub struct BatteryMonitor {
+        policy: BatteryPolicy,
+        discharge_armed: Vec<bool>,
+        charge_armed: Vec<bool>,
+        /// Time and level of latest temperature alert
+        temperature_alert: Option<(Instant, TemperatureLevel)>,
+        health: String,
+    }
+
+    impl BatteryMonitor {
+        /// Thresholds already reached at startup are not alerted
+        pub fn new(policy: BatteryPolicy, status: &BatteryStatus) -> Self {
+            let level = status.get_percentage();
+            Self {
+                discharge_armed: policy
+                    .discharge_thresholds
+                    .iter()
+                    .map(|threshold| level > *threshold)
+                    .collect(),
+                charge_armed: policy
+                    .charge_thresholds
+                    .iter()
+                    .map(|threshold| level < *threshold)
+                    .collect(),
+                temperature_alert: None,
+                health: status.get_health().to_string(),
+                policy,
+            }
+        }
+
+        fn temperature_level(&self, temperature: f32) -> TemperatureLevel {
+            if temperature >= self.policy.temperature_critical {
+                TemperatureLevel::Critical
+            } else if temperature >= self.policy.temperature_warning {
+                TemperatureLevel::Warning
+            } else {
+                TemperatureLevel::Normal
+            }
+        }
+
+        /// Alert when temperature is over a limit, same or lower level is not
+        /// alerted again until cooldown passed, so a temperature bouncing
+        /// around a limit does not flood the chat
+        pub fn check_temperature(
+            &mut self,
+            status: &BatteryStatus,
+            now: Instant,
+        ) -> Option<String> {
+            let temperature = status.get_temperature();
+            let level = self.temperature_level(temperature);
+            if level == TemperatureLevel::Normal {
+                return None;
+            }
+            let cooldown = self.policy.temperature_cooldown;
+            if !self.temperature_alert.map_or(true, |(at, alerted)| {
+                level > alerted || now.saturating_duration_since(at) >= cooldown
+            }) {
+                return None;
+            }
+            self.temperature_alert = Some((now, level));
+            Some(match level {
+                TemperatureLevel::Critical => format!(
+                    "Battery temperature is critical: {:.1}°C (limit {:.1}°C).",
+                    temperature, self.policy.temperature_critical
+                ),
+                _ => format!(
+                    "Battery temperature is high: {:.1}°C (limit {:.1}°C).",
+                    temperature, self.policy.temperature_warning
+                ),
+            })
+        }
+
+        /// Alert when reported health changed, e.g. from `GOOD` to `OVERHEAT`
+        pub fn check_health(&mut self, status: &BatteryStatus) -> Option<String> {
+            let health = status.get_health();
+            if health == self.health {
+                return None;
+            }
+            let alert = format!("Battery health changed from {} to {}.", self.health, health);
+            self.health = health.to_string();
+            Some(alert)
+        }
+
+        /// Alert of the furthest threshold crossed since last update, level
+        /// may pass several thresholds between two polls
+        pub fn update(&mut self, status: &BatteryStatus) -> Option<String> {
+            let level = status.get_percentage();
+            let hysteresis = self.policy.hysteresis;
+
+            let mut low = None;
+            for (threshold, armed) in self
+                .policy
+                .discharge_thresholds
+                .iter()
+                .zip(self.discharge_armed.iter_mut())
+            {
+                if *armed && level <= *threshold {
+                    *armed = false;
+                    low = Some(low.unwrap_or(*threshold).min(*threshold));
+                } else if !*armed && level > threshold + hysteresis {
+                    *armed = true;
+                }
+            }
+
+            let mut high = None;
+            for (threshold, armed) in self
+                .policy
+                .charge_thresholds
+                .iter()
+                .zip(self.charge_armed.iter_mut())
+            {
+                if *armed && level >= *threshold {
+                    *armed = false;
+                    high = Some(high.unwrap_or(*threshold).max(*threshold));
+                } else if !*armed && level < threshold - hysteresis {
+                    *armed = true;
+                }
+            }
+
+            match (low, high) {
+                (Some(threshold), _) => Some(format!(
+                    "Battery is low: {}% (threshold {}%).",
+                    level, threshold
+                )),
+                (None, Some(threshold)) => Some(format!(
+                    "Battery has been charged to {}% (threshold {}%).",
+                    level, threshold
+                )),
+                (None, None) => None,
+            }
+        }
+    }
+
     #[derive(Debug, Clone)]
     pub enum StatusDiff {
+        PowerSource,
         ChargeStatus,
         Battery,
         Equal,
@@ -195,6 +756,7 @@ pub mod battery {
         fn default() -> Self {
             Self {
                 charge_status: BatteryChangerStatus::Discharging,
+                power_source: PowerSource::Unplugged,
                 battery_level: Default::default(),
             }
         }
@@ -202,41 +764,127 @@ pub mod battery {
 }
 
 pub mod sms {
-    use super::{convert_string_to_timestamp, Identifier};
+    use std::convert::TryFrom;
+
+    use super::{
+        convert_records, convert_string_to_timestamp, format_sim_id, Identifier, ParseError,
+    };
+    use chrono_tz::Tz;
     use serde::Deserialize;
 
-    #[allow(dead_code)]
     #[derive(Deserialize, Clone, Debug)]
     pub struct RawMessage {
         threadid: u64,
         #[serde(rename = "type")]
         message_type: String,
         read: bool,
+        /// Contact name, only printed if number is in contacts
+        #[serde(default)]
+        sender: Option<String>,
         number: String,
         received: String,
         body: String,
+        #[serde(default)]
+        sim_id: Option<serde_json::Value>,
     }
 
-    #[allow(dead_code)]
     #[derive(Deserialize, Clone, Debug)]
     pub struct RawMessageList(Vec<RawMessage>);
 
     impl RawMessageList {
-        pub fn convert_to_vec(&self) -> Vec<Message> {
-            self.0
-                .iter()
-                .map(|element| Message::from(element))
-                .collect()
+        pub fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        pub fn convert_to_vec(&self, timezone: &Tz) -> Vec<Message> {
+            convert_records(&self.0, timezone)
+        }
+    }
+
+    /// Box of message, `termux-sms-list -t` accepts all of them except `failed`
+    /// and `queued`, which are only listed by `-t all`
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum MessageBox {
+        Inbox,
+        Sent,
+        Draft,
+        Outbox,
+        Failed,
+        Queued,
+        Unknown,
+    }
+
+    impl MessageBox {
+        /// Name used by termux-api
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                MessageBox::Inbox => "inbox",
+                MessageBox::Sent => "sent",
+                MessageBox::Draft => "draft",
+                MessageBox::Outbox => "outbox",
+                MessageBox::Failed => "failed",
+                MessageBox::Queued => "queued",
+                MessageBox::Unknown => "unknown",
+            }
+        }
+
+        /// Argument of `termux-sms-list -t` which list every box of `boxes`
+        pub fn get_list_type(boxes: &[MessageBox]) -> &'static str {
+            match boxes {
+                [MessageBox::Inbox] => "inbox",
+                [MessageBox::Sent] => "sent",
+                [MessageBox::Draft] => "draft",
+                [MessageBox::Outbox] => "outbox",
+                _ => "all",
+            }
+        }
+    }
+
+    impl TryFrom<&str> for MessageBox {
+        type Error = ParseError;
+
+        fn try_from(s: &str) -> Result<Self, Self::Error> {
+            Ok(match s {
+                "inbox" => Self::Inbox,
+                "sent" => Self::Sent,
+                "draft" => Self::Draft,
+                "outbox" => Self::Outbox,
+                "failed" => Self::Failed,
+                "queued" => Self::Queued,
+                "unknown" => Self::Unknown,
+                _ => return Err(ParseError::MessageBox(s.to_string())),
+            })
+        }
+    }
+
+    impl std::fmt::Display for MessageBox {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "{}",
+                match self {
+                    MessageBox::Inbox => "Receive SMS",
+                    MessageBox::Sent => "Sent SMS",
+                    MessageBox::Draft => "Draft SMS",
+                    MessageBox::Outbox => "Sending SMS",
+                    MessageBox::Failed => "Failed SMS",
+                    MessageBox::Queued => "Queued SMS",
+                    MessageBox::Unknown => "SMS",
+                }
+            )
         }
     }
 
-    #[allow(dead_code)]
     #[derive(Clone, Debug)]
     pub struct Message {
         threadid: u64,
+        message_box: MessageBox,
         read: bool,
+        sender: Option<String>,
         number: String,
+        sim_id: Option<String>,
         timestamp: i64,
+        local_timestamp: i64,
         body: String,
     }
 
@@ -248,17 +896,57 @@ pub mod sms {
         pub fn get_content(&self) -> &String {
             &self.body
         }
+
+        pub fn get_box(&self) -> MessageBox {
+            self.message_box
+        }
+
+        pub fn get_thread_id(&self) -> u64 {
+            self.threadid
+        }
+
+        pub fn get_sender(&self) -> Option<&String> {
+            self.sender.as_ref()
+        }
+
+        pub fn get_sim_id(&self) -> Option<&String> {
+            self.sim_id.as_ref()
+        }
+
+        pub fn is_read(&self) -> bool {
+            self.read
+        }
+
+        /// Receiver of outgoing message is shown as `To`
+        pub fn get_title(&self) -> String {
+            format!(
+                "[{}]\n{}: {}",
+                self.message_box,
+                match self.message_box {
+                    MessageBox::Inbox | MessageBox::Unknown => "From",
+                    _ => "To",
+                },
+                self.number
+            )
+        }
     }
 
-    impl From<&RawMessage> for Message {
-        fn from(m: &RawMessage) -> Self {
-            Self {
+    impl TryFrom<(&RawMessage, &Tz)> for Message {
+        type Error = ParseError;
+
+        fn try_from((m, timezone): (&RawMessage, &Tz)) -> Result<Self, Self::Error> {
+            let (timestamp, local_timestamp) = convert_string_to_timestamp(&m.received, timezone)?;
+            Ok(Self {
                 threadid: m.threadid,
+                message_box: MessageBox::try_from(m.message_type.as_str())?,
                 read: m.read,
+                sender: m.sender.clone(),
                 number: m.number.clone(),
-                timestamp: convert_string_to_timestamp(&m.received).unwrap(),
+                sim_id: format_sim_id(&m.sim_id),
+                timestamp,
+                local_timestamp,
                 body: m.body.clone(),
-            }
+            })
         }
     }
 
@@ -267,17 +955,36 @@ pub mod sms {
             self.timestamp
         }
 
+        fn get_local_timestamp(&self) -> i64 {
+            self.local_timestamp
+        }
+
         fn get_body(&self) -> String {
             format!("{}{}", self.number, self.body)
         }
+
+        fn get_unique_fields(&self) -> String {
+            format!(
+                "{}\0{:?}\0{}\0{}\0{}",
+                self.threadid,
+                self.message_box,
+                self.sim_id.as_deref().unwrap_or_default(),
+                self.number,
+                self.body
+            )
+        }
     }
 }
 
 pub mod call_log {
-    use super::{convert_string_to_timestamp, Identifier};
+    use std::convert::TryFrom;
+
+    use super::{
+        convert_records, convert_string_to_timestamp, format_sim_id, Identifier, ParseError,
+    };
+    use chrono_tz::Tz;
     use serde::Deserialize;
 
-    #[allow(dead_code)]
     #[derive(Deserialize, Clone, Debug)]
     pub struct RawCallLog {
         name: String,
@@ -286,9 +993,10 @@ pub mod call_log {
         log_type: String,
         date: String,
         duration: String,
+        #[serde(default)]
+        sim_id: Option<serde_json::Value>,
     }
 
-    #[allow(dead_code)]
     #[derive(Deserialize, Clone, Debug)]
     pub struct RawCallLogList(Vec<RawCallLog>);
 
@@ -297,31 +1005,56 @@ pub mod call_log {
             self.0.len()
         }
 
-        pub fn convert_to_vec(&self) -> Vec<CallLog> {
-            self.0
-                .iter()
-                .map(|element| CallLog::from(element))
-                .collect()
+        pub fn convert_to_vec(&self, timezone: &Tz) -> Vec<CallLog> {
+            convert_records(&self.0, timezone)
         }
     }
 
+    #[allow(clippy::upper_case_acronyms, non_camel_case_types)]
     #[derive(Clone, Debug, PartialEq)]
     pub enum CallLogType {
         INCOMING,
         OUTGOING,
         REJECTED,
         MISSED,
+        VOICEMAIL,
+        BLOCKED,
+        ANSWERED_EXTERNALLY,
+        UNKNOWN,
     }
 
     impl CallLogType {
-        pub fn parse_type(t: &str) -> Self {
-            match t {
+        /// Name used by termux-api
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                Self::INCOMING => "INCOMING",
+                Self::OUTGOING => "OUTGOING",
+                Self::REJECTED => "REJECTED",
+                Self::MISSED => "MISSED",
+                Self::VOICEMAIL => "VOICEMAIL",
+                Self::BLOCKED => "BLOCKED",
+                Self::ANSWERED_EXTERNALLY => "ANSWERED_EXTERNALLY",
+                Self::UNKNOWN => "UNKNOWN",
+            }
+        }
+    }
+
+    impl TryFrom<&str> for CallLogType {
+        type Error = ParseError;
+
+        fn try_from(t: &str) -> Result<Self, Self::Error> {
+            Ok(match t {
                 "MISSED" => Self::MISSED,
                 "REJECTED" => Self::REJECTED,
                 "OUTGOING" => Self::OUTGOING,
                 "INCOMING" => Self::INCOMING,
-                _ => unreachable!("SHOULD UPDATE CALL LOG TYPE: {}", t),
-            }
+                "VOICEMAIL" => Self::VOICEMAIL,
+                "BLOCKED" => Self::BLOCKED,
+                "ANSWERED_EXTERNALLY" => Self::ANSWERED_EXTERNALLY,
+                // termux-api print `UNKNOWN_TYPE` for type it does not know
+                "UNKNOWN" | "UNKNOWN_TYPE" => Self::UNKNOWN,
+                _ => return Err(ParseError::CallLogType(t.to_string())),
+            })
         }
     }
 
@@ -330,19 +1063,26 @@ pub mod call_log {
         name: String,
         log_type: CallLogType,
         timestamp: i64,
+        local_timestamp: i64,
         phone_number: String,
         duration: String,
+        sim_id: Option<String>,
     }
 
-    impl From<&RawCallLog> for CallLog {
-        fn from(l: &RawCallLog) -> Self {
-            Self {
+    impl TryFrom<(&RawCallLog, &Tz)> for CallLog {
+        type Error = ParseError;
+
+        fn try_from((l, timezone): (&RawCallLog, &Tz)) -> Result<Self, Self::Error> {
+            let (timestamp, local_timestamp) = convert_string_to_timestamp(&l.date, timezone)?;
+            Ok(Self {
                 name: l.name.clone(),
-                log_type: CallLogType::parse_type(&l.log_type),
-                timestamp: convert_string_to_timestamp(&l.date).unwrap(),
+                log_type: CallLogType::try_from(l.log_type.as_str())?,
+                timestamp,
+                local_timestamp,
                 phone_number: l.phone_number.clone(),
                 duration: l.duration.clone(),
-            }
+                sim_id: format_sim_id(&l.sim_id),
+            })
         }
     }
 
@@ -354,11 +1094,17 @@ pub mod call_log {
         pub fn get_number(&self) -> &String {
             &self.phone_number
         }
-    }
 
-    impl From<&RawCallLogList> for Vec<CallLog> {
-        fn from(l: &RawCallLogList) -> Self {
-            l.convert_to_vec()
+        pub fn get_name(&self) -> &String {
+            &self.name
+        }
+
+        pub fn get_duration(&self) -> &String {
+            &self.duration
+        }
+
+        pub fn get_sim_id(&self) -> Option<&String> {
+            self.sim_id.as_ref()
         }
     }
 
@@ -367,36 +1113,31 @@ pub mod call_log {
             self.timestamp
         }
 
+        fn get_local_timestamp(&self) -> i64 {
+            self.local_timestamp
+        }
+
         fn get_body(&self) -> String {
             self.phone_number.clone()
         }
+
+        fn get_unique_fields(&self) -> String {
+            format!(
+                "{:?}\0{}\0{}\0{}",
+                self.log_type,
+                self.sim_id.as_deref().unwrap_or_default(),
+                self.phone_number,
+                self.duration
+            )
+        }
     }
 }
 
 pub mod device_info {
     use serde::Deserialize;
 
-    #[allow(dead_code)]
     #[derive(Deserialize, Clone, Debug)]
     pub struct RawDeviceInfo {
-        data_enabled: String,
-        data_activity: String,
-        data_state: String,
-        device_id: Option<String>,
-        // IMEI
-        device_software_version: String,
-        phone_count: u8,
-        phone_type: String,
-        network_operator: String,
-        network_operator_name: String,
-        network_country_iso: String,
-        network_type: String,
-        network_roaming: bool,
-        sim_country_iso: String,
-        sim_operator: String,
-        sim_operator_name: String,
-        sim_serial_number: Option<String>,
-        sim_subscriber_id: Option<String>,
         sim_state: String,
     }
 
@@ -442,11 +1183,17 @@ pub mod device_info {
 }
 
 pub mod notification {
+    use std::convert::TryFrom;
+
+    use super::{convert_records, convert_string_to_timestamp, Identifier, ParseError};
+    use anyhow::Result;
+    use chrono_tz::Tz;
+    use regex::Regex;
     use serde::Deserialize;
 
     #[allow(dead_code, non_snake_case)]
     #[derive(Deserialize, Clone, Debug)]
-    struct RawNotification {
+    pub struct RawNotification {
         id: i64,
         tag: String,
         key: String,
@@ -455,14 +1202,234 @@ pub mod notification {
         title: String,
         content: String,
         when: String,
+        /// Not printed by every termux-api version
+        #[serde(default)]
+        ongoing: bool,
+    }
+
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct RawNotificationList(Vec<RawNotification>);
+
+    impl RawNotificationList {
+        pub fn convert_to_vec(&self, timezone: &Tz) -> Vec<Notification> {
+            let mut notifications = convert_records(&self.0, timezone);
+            mark_group_summaries(&mut notifications);
+            notifications
+        }
+    }
+
+    /// Group of summary which android posts for notifications it bundled
+    const ANDROID_BUNDLE_GROUP: &str = "ranker_group";
+
+    /// termux-api does not print notification flags, so summaries are told by
+    /// group. Android bundles notifications of an app under a summary of
+    /// [`ANDROID_BUNDLE_GROUP`], while bundled ones keep their own group. For
+    /// a group posted by app, active notifications are sorted with summary
+    /// first, so first one of a group which has other members is its summary.
+    fn mark_group_summaries(notifications: &mut [Notification]) {
+        let summaries: Vec<bool> = notifications
+            .iter()
+            .enumerate()
+            .map(|(index, notification)| {
+                let same_group = |other: &Notification| {
+                    other.package_name == notification.package_name
+                        && other.group == notification.group
+                };
+                notification.group == ANDROID_BUNDLE_GROUP
+                    || (!notification.group.is_empty()
+                        && !notifications[..index].iter().any(same_group)
+                        && notifications[index + 1..].iter().any(same_group))
+            })
+            .collect();
+        for (notification, summary) in notifications.iter_mut().zip(summaries) {
+            notification.group_summary = summary;
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct Notification {
+        key: String,
+        group: String,
+        package_name: String,
+        title: String,
+        content: String,
+        timestamp: i64,
+        local_timestamp: i64,
+        ongoing: bool,
+        group_summary: bool,
+    }
+
+    impl Notification {
+        pub fn get_key(&self) -> &String {
+            &self.key
+        }
+
+        pub fn is_ongoing(&self) -> bool {
+            self.ongoing
+        }
+
+        /// Only known for notifications listed together, see
+        /// [`RawNotificationList::convert_to_vec`]
+        pub fn is_group_summary(&self) -> bool {
+            self.group_summary
+        }
+
+        pub fn get_package_name(&self) -> &String {
+            &self.package_name
+        }
+
+        pub fn get_title(&self) -> &String {
+            &self.title
+        }
+
+        pub fn get_content(&self) -> &String {
+            &self.content
+        }
+    }
+
+    impl TryFrom<(&RawNotification, &Tz)> for Notification {
+        type Error = ParseError;
+
+        fn try_from(
+            (n, timezone): (&RawNotification, &Tz),
+        ) -> std::result::Result<Self, Self::Error> {
+            let (timestamp, local_timestamp) = convert_string_to_timestamp(&n.when, timezone)?;
+            Ok(Self {
+                key: n.key.clone(),
+                group: n.group.clone(),
+                package_name: n.packageName.clone(),
+                title: n.title.clone(),
+                content: n.content.clone(),
+                timestamp,
+                local_timestamp,
+                ongoing: n.ongoing,
+                group_summary: false,
+            })
+        }
+    }
+
+    impl Identifier for Notification {
+        fn get_timestamp(&self) -> i64 {
+            self.timestamp
+        }
+
+        fn get_local_timestamp(&self) -> i64 {
+            self.local_timestamp
+        }
+
+        fn get_body(&self) -> String {
+            format!(
+                "{}{}{}{}",
+                self.package_name, self.key, self.title, self.content
+            )
+        }
+    }
+
+    fn default_true() -> bool {
+        true
+    }
+
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct RawApplicationFilter {
+        package: String,
+        /// Name display in forwarded message instead of package name
+        name: Option<String>,
+        include_title: Option<String>,
+        exclude_title: Option<String>,
+        include_content: Option<String>,
+        exclude_content: Option<String>,
+        #[serde(default = "default_true")]
+        ignore_ongoing: bool,
+        #[serde(default = "default_true")]
+        ignore_group_summary: bool,
     }
 
-    #[allow(dead_code)]
     #[derive(Deserialize, Clone, Debug)]
-    struct RawNotificationList(Vec<RawNotification>);
+    #[serde(untagged)]
+    pub enum RawApplicationRule {
+        Package(String),
+        Filter(RawApplicationFilter),
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct ApplicationRule {
+        package: String,
+        name: Option<String>,
+        include_title: Option<Regex>,
+        exclude_title: Option<Regex>,
+        include_content: Option<Regex>,
+        exclude_content: Option<Regex>,
+        ignore_ongoing: bool,
+        ignore_group_summary: bool,
+    }
+
+    fn compile(pattern: &Option<String>) -> Result<Option<Regex>> {
+        Ok(match pattern {
+            Some(pattern) => Some(Regex::new(pattern)?),
+            None => None,
+        })
+    }
+
+    impl TryFrom<&RawApplicationRule> for ApplicationRule {
+        type Error = anyhow::Error;
+
+        fn try_from(rule: &RawApplicationRule) -> Result<Self> {
+            Ok(match rule {
+                RawApplicationRule::Package(package) => Self {
+                    package: package.clone(),
+                    name: None,
+                    include_title: None,
+                    exclude_title: None,
+                    include_content: None,
+                    exclude_content: None,
+                    ignore_ongoing: true,
+                    ignore_group_summary: true,
+                },
+                RawApplicationRule::Filter(filter) => Self {
+                    package: filter.package.clone(),
+                    name: filter.name.clone(),
+                    include_title: compile(&filter.include_title)?,
+                    exclude_title: compile(&filter.exclude_title)?,
+                    include_content: compile(&filter.include_content)?,
+                    exclude_content: compile(&filter.exclude_content)?,
+                    ignore_ongoing: filter.ignore_ongoing,
+                    ignore_group_summary: filter.ignore_group_summary,
+                },
+            })
+        }
+    }
+
+    impl ApplicationRule {
+        pub fn get_display_name(&self) -> &String {
+            self.name.as_ref().unwrap_or(&self.package)
+        }
+
+        pub fn is_match(&self, notification: &Notification) -> bool {
+            if self.package.ne(notification.get_package_name())
+                || (self.ignore_ongoing && notification.is_ongoing())
+                || (self.ignore_group_summary && notification.is_group_summary())
+            {
+                return false;
+            }
+            let check = |include: &Option<Regex>, exclude: &Option<Regex>, s: &str| {
+                include.as_ref().map_or(true, |r| r.is_match(s))
+                    && !exclude.as_ref().is_some_and(|r| r.is_match(s))
+            };
+            check(
+                &self.include_title,
+                &self.exclude_title,
+                notification.get_title(),
+            ) && check(
+                &self.include_content,
+                &self.exclude_content,
+                notification.get_content(),
+            )
+        }
+    }
 }
 
-pub use battery::{BatteryChangerStatus, BatteryStatus, StatusDiff};
+pub use battery::{BatteryMonitor, BatteryPolicy, BatteryStatus, StatusDiff};
 pub use call_log::{CallLog, CallLogType, RawCallLogList};
-pub use device_info::{RawDeviceInfo, SIMState};
-pub use sms::{Message, RawMessageList};
+pub use device_info::RawDeviceInfo;
+pub use notification::{ApplicationRule, Notification, RawNotificationList};
+pub use sms::{Message, MessageBox, RawMessageList};
@@ -18,67 +18,100 @@
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
 
+mod chart;
 mod database;
 mod datastructures;
 #[cfg(feature = "server")]
 mod server;
+mod telegram;
+mod termux;
 mod test;
 
-use std::time::Duration;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
-use clap::{App, ArgMatches};
-use datastructures::{
-    BatteryStatus, CallLog, Identifier, Message, PermissionError, RawCallLogList, RawMessageList,
+use chrono::{Offset, TimeZone};
+use chrono_tz::Tz;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use database::{crypto::Cipher, retention::RetentionPolicy};
+use datastructures::{ApplicationRule, Configure, Identifier, MessageBox, Notification};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode},
+    ConnectOptions, Connection,
+};
+use telegram::{Bot, Command as BotCommand, Language};
+use termux::{SharedTermuxApi, Termux, TermuxApi, Watchdog};
+use tokio::{
+    signal::ctrl_c,
+    sync::{mpsc, oneshot},
 };
-use sqlx::Connection;
-use tokio::{process::Command, signal::ctrl_c, sync::mpsc};
 
-use crate::datastructures::{BatteryChangerStatus, CallLogType, RawDeviceInfo};
+use crate::datastructures::{
+    device_info::SIMState, format_timestamp, BatteryMonitor, BatteryPolicy, CallLogType,
+};
 
-async fn fetch_sms() -> Result<Vec<Message>> {
-    let output = Command::new("termux-sms-list").output().await?.stdout;
-    let output = String::from_utf8(output)?;
-    let messages: RawMessageList = serde_json::from_str(&output)?;
-    Ok(messages.convert_to_vec())
-}
+/// Database in working directory used by previous versions
+const LEGACY_DATABASE_PATH: &str = "sms_client.db";
 
-async fn fetch_call_log() -> Result<Vec<CallLog>> {
-    let output = Command::new("termux-call-log").output().await?.stdout;
-    let output = String::from_utf8(output)?;
-    let logs: RawCallLogList = serde_json::from_str(&output)?;
-    Ok(logs.convert_to_vec())
-}
+/// Directory of config and database under user's config and data directory
+const APP_DIR: &str = "termux-sms-client";
+const DEFAULT_PROFILE: &str = "default";
 
-async fn fetch_battery_status() -> Result<BatteryStatus> {
-    let output = Command::new("termux-battery-status").output().await?.stdout;
-    let output = String::from_utf8(output)?;
-    if output.contains("Error") {
-        return Err(anyhow::Error::new(PermissionError::new()));
-    }
-    let status = BatteryStatus::from_str(&output)?;
-    Ok(status)
-}
+/// termux is polled this often
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-async fn fetch_device_info() -> Result<RawDeviceInfo> {
-    let output = Command::new("termux-telephony-deviceinfo")
-        .output()
-        .await?
-        .stdout;
-    let output = String::from_utf8(output)?;
-    if output.contains("Error") {
-        return Err(anyhow::Error::new(PermissionError::new()));
-    }
-    Ok(serde_json::from_str(&output)?)
+/// Seen-identifier tables are pruned this often if retention is configured
+const PRUNE_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+
+/// Battery history is downsampled this often
+const DOWNSAMPLE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// `client_meta` keys of newest fetched record timestamps
+const SMS_HIGH_WATER: &str = "sms_high_water";
+const CALL_LOG_HIGH_WATER: &str = "call_log_high_water";
+
+/// Timezone is read from `TZ` environment variable or android system property
+async fn detect_timezone(termux: &dyn TermuxApi) -> Result<Tz> {
+    let name = match std::env::var("TZ") {
+        Ok(name) if !name.is_empty() => name,
+        _ => termux.fetch_system_property("persist.sys.timezone").await?,
+    };
+    name.parse()
+        .map_err(|e| anyhow::anyhow!("Unable to parse timezone {:?}: {}", name, e))
 }
 
-async fn upstream(mut message_rx: mpsc::Receiver<InnerCommand>) -> Result<()> {
+async fn upstream(bot: Bot, mut message_rx: mpsc::Receiver<InnerCommand>) -> Result<()> {
     loop {
         if let Ok(Some(cmd)) = tokio::time::timeout(Duration::from_secs(1), message_rx.recv()).await
         {
             match cmd {
                 InnerCommand::Message(msg) => {
-                    todo!()
+                    if let Err(ref e) = bot.send_message(bot.get_owner(), &msg).await {
+                        log::error!("Got error while send message to upstream: {:?}", e);
+                    }
+                }
+                InnerCommand::TrackedMessage(msg, reply) => {
+                    let message_id = match bot.send_message(bot.get_owner(), &msg).await {
+                        Ok(message) => Some(message.get_message_id()),
+                        Err(ref e) => {
+                            log::error!("Got error while send message to upstream: {:?}", e);
+                            None
+                        }
+                    };
+                    reply.send(message_id).ok();
+                }
+                InnerCommand::EditMessage(message_id, msg, reply) => {
+                    let result = bot
+                        .edit_message_text(bot.get_owner(), message_id, &msg)
+                        .await;
+                    if let Err(ref e) = result {
+                        log::warn!("Unable to edit message {}: {:?}", message_id, e);
+                    }
+                    reply.send(result.is_ok()).ok();
                 }
                 InnerCommand::Terminate => break,
             }
@@ -87,183 +120,1157 @@ async fn upstream(mut message_rx: mpsc::Receiver<InnerCommand>) -> Result<()> {
     Ok(())
 }
 
-async fn query_loop(
+async fn register_commands(bot: &Bot) -> Result<()> {
+    bot.set_my_commands(&BotCommand::to_bot_commands(Language::English), None)
+        .await?;
+    for language in Language::TRANSLATED {
+        bot.set_my_commands(
+            &BotCommand::to_bot_commands(*language),
+            Some(language.get_code()),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Search SMS and format results as reply text
+async fn search_messages(
+    conn: &mut sqlx::sqlite::SqliteConnection,
+    cipher: &Cipher,
+    archive: bool,
+    arguments: &str,
+    timezone: &Tz,
+) -> Result<String> {
+    let query = database::search::SearchQuery::parse(arguments, timezone)?;
+    let results = database::search::search(conn, cipher, archive, &query).await?;
+    Ok(database::search::format_results(&query, &results, timezone))
+}
+
+/// Battery status with estimated time to full or empty, estimate is left out
+/// if history can not be read
+async fn battery_report(
+    conn: &mut sqlx::sqlite::SqliteConnection,
+    status: &datastructures::BatteryStatus,
+) -> String {
+    let since = chrono::Utc::now().timestamp() - database::battery_history::ESTIMATE_HISTORY;
+    let estimate = match database::battery_history::fetch(conn, since).await {
+        Ok(records) => database::battery_history::format_estimate(
+            status,
+            database::battery_history::estimate_remaining(&records, status),
+        ),
+        Err(ref e) => {
+            log::error!("Got error while read battery history: {:?}", e);
+            None
+        }
+    };
+    match estimate {
+        Some(estimate) => format!("{}\n{}", status, estimate),
+        None => status.to_string(),
+    }
+}
+
+/// Battery level sparkline of `[duration]`, duration defaults to 24h and is
+/// at most as long as history is kept
+async fn battery_chart(
+    conn: &mut sqlx::sqlite::SqliteConnection,
+    arguments: &str,
+    timezone: &Tz,
+) -> Result<String> {
+    let duration = match arguments.trim() {
+        "" => 86400,
+        argument => datastructures::parse_duration(argument)?.as_secs() as i64,
+    };
+    if !(3600..=database::battery_history::MAX_AGE).contains(&duration) {
+        return Err(anyhow::anyhow!(
+            "Duration should be between 1h and {}d",
+            database::battery_history::MAX_AGE / 86400
+        ));
+    }
+    let until = chrono::Utc::now().timestamp();
+    let since = until - duration;
+    let records = database::battery_history::fetch(conn, since).await?;
+    let summary = chart::summary(&records).ok_or_else(|| {
+        anyhow::anyhow!(
+            "No battery history since {}",
+            format_timestamp(since, timezone)
+        )
+    })?;
+    Ok(format!(
+        "[Battery] {} - {}\n{}\n{}",
+        format_timestamp(since, timezone),
+        format_timestamp(until, timezone),
+        chart::sparkline(&chart::bucket_levels(&records, since, until, 48)),
+        summary
+    ))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_command(
+    termux: &dyn TermuxApi,
+    conn: &mut sqlx::sqlite::SqliteConnection,
+    cipher: &Cipher,
+    archive: bool,
+    timezone: &Tz,
+    command: BotCommand,
+    arguments: &str,
+    language: Language,
+) -> String {
+    match command {
+        BotCommand::Help => BotCommand::help_text(language),
+        BotCommand::Search => search_messages(conn, cipher, archive, arguments, timezone)
+            .await
+            .unwrap_or_else(|e| format!("[Search] {}", e)),
+        BotCommand::Battery => battery_chart(conn, arguments, timezone)
+            .await
+            .unwrap_or_else(|e| format!("[Battery] {}", e)),
+        BotCommand::Status => {
+            let battery = match termux.fetch_battery_status().await {
+                Ok(status) => battery_report(conn, &status).await,
+                Err(e) => format!("Unable to fetch battery status: {}", e),
+            };
+            let sim = match termux.fetch_device_info().await {
+                Ok(info) => info.get_sim_state().to_string(),
+                Err(e) => format!("unavailable ({})", e),
+            };
+            format!("[System Information]\n{}\nSim card: {}", battery, sim)
+        }
+    }
+}
+
+async fn command_loop(
+    bot: Bot,
+    termux: SharedTermuxApi,
     mut conn: sqlx::sqlite::SqliteConnection,
-    message_tx: mpsc::Sender<InnerCommand>,
+    cipher: Cipher,
+    archive: bool,
+    timezone: Tz,
     mut terminate_rx: mpsc::Receiver<InnerCommand>,
 ) -> Result<()> {
-    let mut battery_status = fetch_battery_status().await?.to_current_status();
-    let mut sim_status = fetch_device_info().await?.get_sim_state();
+    let mut offset = 0;
     loop {
-        let current_battery_status = fetch_battery_status().await?;
-
-        match battery_status.not_equal(&current_battery_status) {
-            datastructures::StatusDiff::ChargeStatus => {
-                message_tx
-                    .send(InnerCommand::Message(format!(
-                        "[System Information]{}",
-                        current_battery_status
-                    )))
+        let updates = tokio::select! {
+            updates = bot.get_updates(offset, 30) => updates,
+            _ = terminate_rx.recv() => break,
+        };
+        let updates = match updates {
+            Ok(updates) => updates,
+            Err(ref e) => {
+                log::error!("Got error while fetch updates: {:?}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+        for update in &updates {
+            offset = update.get_update_id() + 1;
+            let message = match update.get_message() {
+                Some(message) if message.get_chat_id() == bot.get_owner() => message,
+                _ => continue,
+            };
+            if let Some((command, arguments)) =
+                message.get_text().and_then(|text| BotCommand::parse(text))
+            {
+                let language = Language::from_code(message.get_language_code().map(|s| s.as_str()));
+                let reply = handle_command(
+                    termux.as_ref(),
+                    &mut conn,
+                    &cipher,
+                    archive,
+                    &timezone,
+                    command,
+                    arguments,
+                    language,
+                )
+                .await;
+                if let Err(ref e) = bot.send_message(message.get_chat_id(), &reply).await {
+                    log::error!("Got error while reply command: {:?}", e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Notification which share same key with a forwarded one is edited in place
+/// instead of sending a new message.
+async fn forward_notifications(
+    conn: &mut sqlx::sqlite::SqliteConnection,
+    cipher: &Cipher,
+    notifications: &[Notification],
+    applications: &[ApplicationRule],
+    timezone: &Tz,
+    message_tx: &mpsc::Sender<InnerCommand>,
+) -> Result<()> {
+    for notification in notifications {
+        let application = match applications.iter().find(|rule| rule.is_match(notification)) {
+            Some(application) => application,
+            None => continue,
+        };
+        let identifier = cipher.identifier(&notification.get_identifier());
+        let key = cipher.identifier(notification.get_key());
+        if !matches!(
+            sqlx::query(r#"SELECT * FROM "notifications" WHERE "identifier" = ? "#)
+                .bind(&identifier)
+                .fetch_optional(&mut *conn)
+                .await,
+            Ok(None)
+        ) {
+            continue;
+        }
+        let text = format!(
+            "[Notification]\nApplication: {package}\nTitle: {title}\nContent: {content}\nTime: {time}",
+            package = application.get_display_name(),
+            title = notification.get_title(),
+            content = notification.get_content(),
+            time = format_timestamp(notification.get_timestamp(), timezone)
+        );
+
+        let tracked: Option<(i64,)> =
+            sqlx::query_as(r#"SELECT "message_id" FROM "notification_messages" WHERE "key" = ?"#)
+                .bind(&key)
+                .fetch_optional(&mut *conn)
+                .await?;
+        let mut edited = false;
+        if let Some((message_id,)) = tracked {
+            let (tx, rx) = oneshot::channel();
+            message_tx
+                .send(InnerCommand::EditMessage(message_id, text.clone(), tx))
+                .await?;
+            edited = rx.await.unwrap_or(false);
+        }
+        if !edited {
+            let (tx, rx) = oneshot::channel();
+            message_tx
+                .send(InnerCommand::TrackedMessage(text, tx))
+                .await?;
+            if let Ok(Some(message_id)) = rx.await {
+                sqlx::query(r#"INSERT OR REPLACE INTO "notification_messages" VALUES (?, ?, ?)"#)
+                    .bind(&key)
+                    .bind(message_id)
+                    .bind(notification.get_timestamp())
+                    .execute(&mut *conn)
                     .await?;
-                battery_status.update_charge_status(&current_battery_status)
             }
-            datastructures::StatusDiff::Battery => {
-                if current_battery_status.get_percentage() == 15 {
+        }
+
+        if let Err(ref e) = sqlx::query(r#"INSERT INTO "notifications" VALUES (?, ?)"#)
+            .bind(&identifier)
+            .bind(notification.get_timestamp())
+            .execute(&mut *conn)
+            .await
+        {
+            log::error!("Got error while insert notification: {:?}", e);
+        }
+    }
+
+    // Key may be reused after notification dismissed, should not edit the old message then
+    let tracked_keys: Vec<(String,)> =
+        sqlx::query_as(r#"SELECT "key" FROM "notification_messages""#)
+            .fetch_all(&mut *conn)
+            .await?;
+    for (key,) in tracked_keys {
+        if !notifications
+            .iter()
+            .any(|element| cipher.identifier(element.get_key()).eq(&key))
+        {
+            sqlx::query(r#"DELETE FROM "notification_messages" WHERE "key" = ?"#)
+                .bind(&key)
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Timestamp of newest record fetched before, older records are never
+/// forwarded. Fallback to newest stored record if it is not recorded yet.
+///
+/// Records stored before phone timezone was applied saved local time as UTC,
+/// so fallback is moved back by positive offset of `timezone`. Records
+/// fetched again are skipped by `is_seen`.
+async fn get_high_water(
+    conn: &mut sqlx::sqlite::SqliteConnection,
+    key: &str,
+    table: &str,
+    timezone: &Tz,
+) -> Result<Option<i64>> {
+    let stored: Option<(String,)> =
+        sqlx::query_as(r#"SELECT "value" FROM "client_meta" WHERE "key" = ?"#)
+            .bind(key)
+            .fetch_optional(&mut *conn)
+            .await?;
+    if let Some((value,)) = stored {
+        return Ok(Some(value.parse()?));
+    }
+    let (timestamp,): (Option<i64>,) =
+        sqlx::query_as(&format!(r#"SELECT MAX("timestamp") FROM "{}""#, table))
+            .fetch_one(&mut *conn)
+            .await?;
+    Ok(timestamp.map(|timestamp| {
+        let offset = timezone
+            .timestamp_opt(timestamp, 0)
+            .single()
+            .map_or(0, |datetime| datetime.offset().fix().local_minus_utc());
+        timestamp - i64::from(offset.max(0))
+    }))
+}
+
+async fn set_high_water(
+    conn: &mut sqlx::sqlite::SqliteConnection,
+    key: &str,
+    timestamp: i64,
+) -> Result<()> {
+    sqlx::query(r#"INSERT OR REPLACE INTO "client_meta" VALUES (?, ?)"#)
+        .bind(key)
+        .bind(timestamp.to_string())
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Records not newer than this may be stored with identifier v1
+async fn get_legacy_identifier_until(
+    conn: &mut sqlx::sqlite::SqliteConnection,
+) -> Result<Option<i64>> {
+    let stored: Option<(String,)> =
+        sqlx::query_as(r#"SELECT "value" FROM "client_meta" WHERE "key" = ?"#)
+            .bind(database::current::LEGACY_IDENTIFIER_UNTIL)
+            .fetch_optional(&mut *conn)
+            .await?;
+    Ok(match stored {
+        Some((value,)) => Some(value.parse()?),
+        None => None,
+    })
+}
+
+/// Check identifier v2, and identifier v1 if record may be stored before
+/// upgrade. `identifier` should be hashed by `cipher` already.
+async fn is_seen<T: Identifier>(
+    conn: &mut sqlx::sqlite::SqliteConnection,
+    cipher: &Cipher,
+    table: &str,
+    record: &T,
+    identifier: &str,
+    legacy_until: Option<i64>,
+) -> Result<bool, sqlx::Error> {
+    let legacy_identifier = match legacy_until {
+        Some(until) if record.get_timestamp() <= until => {
+            cipher.identifier(&record.get_identifier())
+        }
+        _ => identifier.to_string(),
+    };
+    Ok(sqlx::query(&format!(
+        r#"SELECT * FROM "{}" WHERE "identifier" IN (?, ?)"#,
+        table
+    ))
+    .bind(identifier)
+    .bind(legacy_identifier)
+    .fetch_optional(&mut *conn)
+    .await?
+    .is_some())
+}
+
+/// Identifiers of records not older than returned one should be kept,
+/// `i64::MAX` if nothing is returned.
+fn get_keep_since<T: Identifier>(records: &[T], high_water: Option<i64>) -> i64 {
+    records
+        .iter()
+        .map(Identifier::get_timestamp)
+        .chain(high_water)
+        .min()
+        .unwrap_or(i64::MAX)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn query_loop(
+    mut conn: sqlx::sqlite::SqliteConnection,
+    termux: SharedTermuxApi,
+    applications: Vec<ApplicationRule>,
+    sms_boxes: Vec<MessageBox>,
+    archive: bool,
+    cipher: Cipher,
+    retention: RetentionPolicy,
+    battery_policy: BatteryPolicy,
+    timezone: Tz,
+    mut watchdog: Watchdog,
+    message_tx: mpsc::Sender<InnerCommand>,
+    mut tick_rx: mpsc::Receiver<()>,
+    mut terminate_rx: mpsc::Receiver<InnerCommand>,
+) -> Result<()> {
+    let legacy_until = get_legacy_identifier_until(&mut conn).await?;
+    let mut last_prune: Option<Instant> = None;
+    let mut last_downsample: Option<Instant> = None;
+    // Oldest record returned by latest successful fetch
+    let (mut keep_messages, mut keep_call_logs, mut keep_notifications) = (None, None, None);
+    // State is unknown until termux-api responds, a call hung at startup is
+    // alerted like the ones in loop instead of stopping client
+    let result = termux.fetch_battery_status().await;
+    if let Some(alert) = watchdog.record("termux-battery-status", &result) {
+        message_tx.send(InnerCommand::Message(alert)).await?;
+    }
+    let mut battery = match result {
+        Ok(status) => Some((
+            status.to_current_status(),
+            BatteryMonitor::new(battery_policy.clone(), &status),
+        )),
+        Err(ref e) => {
+            log::error!("Got error while fetch battery status: {:?}", e);
+            None
+        }
+    };
+    let result = termux.fetch_device_info().await;
+    if let Some(alert) = watchdog.record("termux-telephony-deviceinfo", &result) {
+        message_tx.send(InnerCommand::Message(alert)).await?;
+    }
+    let mut sim_status = match result {
+        Ok(device_info) => device_info.get_sim_state(),
+        Err(ref e) => {
+            log::error!("Got error while fetch device info: {:?}", e);
+            SIMState::Unknown
+        }
+    };
+    loop {
+        let result = termux.fetch_battery_status().await;
+        if let Some(alert) = watchdog.record("termux-battery-status", &result) {
+            message_tx.send(InnerCommand::Message(alert)).await?;
+        }
+        match result {
+            Ok(current_battery_status) => {
+                let (battery_status, battery_monitor) = battery.get_or_insert_with(|| {
+                    (
+                        current_battery_status.to_current_status(),
+                        BatteryMonitor::new(battery_policy.clone(), &current_battery_status),
+                    )
+                });
+                if let Err(ref e) = database::battery_history::record(
+                    &mut conn,
+                    &current_battery_status,
+                    chrono::Utc::now().timestamp(),
+                )
+                .await
+                {
+                    log::error!("Got error while record battery status: {:?}", e);
+                }
+                let change = match battery_status.not_equal(&current_battery_status) {
+                    datastructures::StatusDiff::PowerSource => Some(format!(
+                        "Power source changed: {} -> {}\n",
+                        battery_status.get_power_source(),
+                        current_battery_status.get_power_source()
+                    )),
+                    datastructures::StatusDiff::ChargeStatus => Some(String::new()),
+                    _ => None,
+                };
+                if let Some(change) = change {
+                    let report = battery_report(&mut conn, &current_battery_status).await;
+                    message_tx
+                        .send(InnerCommand::Message(format!(
+                            "[System Information]\n{}{}",
+                            change, report
+                        )))
+                        .await?;
+                }
+                let alerts = vec![
+                    battery_monitor.update(&current_battery_status),
+                    battery_monitor.check_temperature(&current_battery_status, Instant::now()),
+                    battery_monitor.check_health(&current_battery_status),
+                ];
+                for alert in alerts.into_iter().flatten() {
                     message_tx
                         .send(InnerCommand::Message(format!(
                             "[System Information]\n{}",
-                            match current_battery_status.get_changer_status() {
-                                BatteryChangerStatus::Charging => "Battery is low.",
-                                BatteryChangerStatus::Discharging =>
-                                    "Battery has been charged to a safe level.",
-                            }
+                            alert
                         )))
                         .await?;
                 }
                 battery_status.update_charge_status(&current_battery_status)
             }
-            datastructures::StatusDiff::Equal => todo!(),
+            Err(ref e) => log::error!("Got error while fetch battery status: {:?}", e),
         }
 
-        let current_sim_status = fetch_device_info().await?.get_sim_state();
-
-        if current_sim_status != sim_status {
-            message_tx
-                .send(InnerCommand::Message(format!(
-                    "[System information]Sim card {status}",
-                    status = current_sim_status
-                )))
-                .await?;
-            sim_status = current_sim_status;
+        let result = termux.fetch_device_info().await;
+        if let Some(alert) = watchdog.record("termux-telephony-deviceinfo", &result) {
+            message_tx.send(InnerCommand::Message(alert)).await?;
         }
-
-        if let Ok(short_messages) = fetch_sms().await {
-            for message in &short_messages {
-                let identifier = message.get_identifier();
-                if let Ok(None) = sqlx::query(r#"SELECT * FROM "messages" WHERE "identifier" = ? "#)
-                    .bind(&identifier)
-                    .fetch_optional(&mut conn)
-                    .await
-                {
+        match result {
+            Ok(device_info) => {
+                let current_sim_status = device_info.get_sim_state();
+                if current_sim_status != sim_status {
                     message_tx
                         .send(InnerCommand::Message(format!(
-                            "[Receive SMS]\nFrom: {sender}\nContent: {content}",
-                            sender = message.get_number(),
-                            content = message.get_content()
+                            "[System information]Sim card {status}",
+                            status = current_sim_status
                         )))
                         .await?;
-                    if let Err(ref e) = sqlx::query(r#"INSERT INTO "messages" VALUES (?, ?)"#)
-                        .bind(&identifier)
-                        .bind(message.get_timestamp())
-                        .execute(&mut conn)
+                    sim_status = current_sim_status;
+                }
+            }
+            Err(ref e) => log::error!("Got error while fetch device info: {:?}", e),
+        }
+
+        let high_water = get_high_water(&mut conn, SMS_HIGH_WATER, "messages", &timezone).await?;
+        let result = termux
+            .fetch_sms_since(&timezone, &sms_boxes, high_water)
+            .await;
+        if let Some(alert) = watchdog.record("termux-sms-list", &result) {
+            message_tx.send(InnerCommand::Message(alert)).await?;
+        }
+        match result {
+            Ok(mut short_messages) => {
+                keep_messages = Some(get_keep_since(&short_messages, high_water));
+                short_messages.retain(|message| {
+                    high_water.map_or(true, |high_water| message.get_timestamp() >= high_water)
+                });
+                short_messages.sort_by_key(|message| message.get_timestamp());
+                let identifiers = datastructures::get_identifiers(&short_messages);
+                for (message, identifier) in short_messages.iter().zip(identifiers) {
+                    let identifier = cipher.identifier(&identifier);
+                    if archive {
+                        if let Err(ref e) = database::archive::insert_message(
+                            &mut conn,
+                            &cipher,
+                            &identifier,
+                            message,
+                        )
                         .await
+                        {
+                            log::error!("Got error while archive message: {:?}", e);
+                        }
+                    }
+                    if let Ok(false) = is_seen(
+                        &mut conn,
+                        &cipher,
+                        "messages",
+                        message,
+                        &identifier,
+                        legacy_until,
+                    )
+                    .await
                     {
-                        log::error!("Got error while insert message: {:?}", e);
+                        if !cipher.is_enabled() {
+                            if let Err(ref e) =
+                                database::search::index_message(&mut conn, &identifier, message)
+                                    .await
+                            {
+                                log::error!("Got error while index message: {:?}", e);
+                            }
+                        }
+                        message_tx
+                            .send(InnerCommand::Message(format!(
+                                "{title}\nContent: {content}\nTime: {time}",
+                                title = message.get_title(),
+                                content = message.get_content(),
+                                time = format_timestamp(message.get_timestamp(), &timezone)
+                            )))
+                            .await?;
+                        if let Err(ref e) = sqlx::query(r#"INSERT INTO "messages" VALUES (?, ?)"#)
+                            .bind(&identifier)
+                            .bind(message.get_timestamp())
+                            .execute(&mut conn)
+                            .await
+                        {
+                            log::error!("Got error while insert message: {:?}", e);
+                        }
                     }
                 }
+                if let Some(message) = short_messages.last() {
+                    set_high_water(&mut conn, SMS_HIGH_WATER, message.get_timestamp()).await?;
+                }
             }
+            Err(ref e) => log::error!("Got error while fetch sms: {}", e),
         }
 
-        if let Ok(call_logs) = fetch_call_log().await {
-            for call_log in &call_logs {
-                if call_log.get_log_type() != &CallLogType::MISSED {
-                    continue;
-                }
-                let identifier = call_log.get_identifier();
-                if let Ok(None) =
-                    sqlx::query(r#"SELECT * FROM "call_logs" WHERE "identifier" = ? "#)
-                        .bind(&identifier)
-                        .fetch_optional(&mut conn)
+        let high_water =
+            get_high_water(&mut conn, CALL_LOG_HIGH_WATER, "call_logs", &timezone).await?;
+        let result = termux.fetch_call_log_since(&timezone, high_water).await;
+        if let Some(alert) = watchdog.record("termux-call-log", &result) {
+            message_tx.send(InnerCommand::Message(alert)).await?;
+        }
+        match result {
+            Ok(mut call_logs) => {
+                keep_call_logs = Some(get_keep_since(&call_logs, high_water));
+                call_logs.retain(|call_log| {
+                    high_water.map_or(true, |high_water| call_log.get_timestamp() >= high_water)
+                });
+                call_logs.sort_by_key(|call_log| call_log.get_timestamp());
+                let identifiers = datastructures::get_identifiers(&call_logs);
+                for (call_log, identifier) in call_logs.iter().zip(identifiers) {
+                    let identifier = cipher.identifier(&identifier);
+                    if archive {
+                        if let Err(ref e) = database::archive::insert_call_log(
+                            &mut conn,
+                            &cipher,
+                            &identifier,
+                            call_log,
+                        )
                         .await
-                {
-                    message_tx
-                        .send(InnerCommand::Message(format!(
-                            "[Missed Call]\nCall from: {number}",
-                            number = call_log.get_number()
-                        )))
+                        {
+                            log::error!("Got error while archive call log: {:?}", e);
+                        }
+                    }
+                    if call_log.get_log_type() != &CallLogType::MISSED {
+                        continue;
+                    }
+                    if let Ok(false) = is_seen(
+                        &mut conn,
+                        &cipher,
+                        "call_logs",
+                        call_log,
+                        &identifier,
+                        legacy_until,
+                    )
+                    .await
+                    {
+                        message_tx
+                            .send(InnerCommand::Message(format!(
+                                "[Missed Call]\nCall from: {number}\nTime: {time}",
+                                number = call_log.get_number(),
+                                time = format_timestamp(call_log.get_timestamp(), &timezone)
+                            )))
+                            .await?;
+                        if let Err(ref e) = sqlx::query(r#"INSERT INTO "call_logs" VALUES (?, ?)"#)
+                            .bind(&identifier)
+                            .bind(call_log.get_timestamp())
+                            .execute(&mut conn)
+                            .await
+                        {
+                            log::error!("Got error while insert call log: {:?}", e);
+                        }
+                    }
+                }
+                if let Some(call_log) = call_logs.last() {
+                    set_high_water(&mut conn, CALL_LOG_HIGH_WATER, call_log.get_timestamp())
                         .await?;
-                    if let Err(ref e) = sqlx::query(r#"INSERT INTO "call_logs" VALUES (?, ?)"#)
-                        .execute(&mut conn)
-                        .await
+                }
+            }
+            Err(ref e) => log::error!("Got error while fetch call log: {}", e),
+        }
+
+        if !applications.is_empty() {
+            let result = termux.fetch_notifications(&timezone).await;
+            if let Some(alert) = watchdog.record("termux-notification-list", &result) {
+                message_tx.send(InnerCommand::Message(alert)).await?;
+            }
+            match result {
+                Ok(notifications) => {
+                    keep_notifications = Some(get_keep_since(&notifications, None));
+                    if let Err(ref e) = forward_notifications(
+                        &mut conn,
+                        &cipher,
+                        &notifications,
+                        &applications,
+                        &timezone,
+                        &message_tx,
+                    )
+                    .await
                     {
-                        log::error!("Got error while insert call log: {:?}", e);
+                        log::error!("Got error while forward notifications: {:?}", e);
                     }
                 }
+                Err(ref e) => log::error!("Got error while fetch notifications: {}", e),
+            }
+        }
+
+        if last_downsample.map_or(true, |last| last.elapsed() >= DOWNSAMPLE_INTERVAL) {
+            last_downsample = Some(Instant::now());
+            if let Err(ref e) =
+                database::battery_history::downsample(&mut conn, chrono::Utc::now().timestamp())
+                    .await
+            {
+                log::error!("Got error while downsample battery history: {:?}", e);
             }
         }
 
-        if let Ok(Some(cmd)) =
-            tokio::time::timeout(Duration::from_secs(1), terminate_rx.recv()).await
+        if retention.is_enabled()
+            && last_prune.map_or(true, |last| last.elapsed() >= PRUNE_INTERVAL)
         {
-            match cmd {
-                InnerCommand::Terminate => break,
+            last_prune = Some(Instant::now());
+            if let Err(ref e) = database::retention::prune_all(
+                &mut conn,
+                &retention,
+                &[
+                    ("messages", keep_messages),
+                    ("call_logs", keep_call_logs),
+                    ("notifications", keep_notifications),
+                ],
+                chrono::Utc::now().timestamp(),
+            )
+            .await
+            {
+                log::error!("Got error while prune database: {:?}", e);
+            }
+        }
+
+        // Terminate is checked first, ticks may be always pending if polling
+        // takes longer than tick period
+        tokio::select! {
+            biased;
+            cmd = terminate_rx.recv() => match cmd {
+                Some(InnerCommand::Terminate) | None => break,
                 _ => unreachable!(),
+            },
+            tick = tick_rx.recv() => {
+                if tick.is_none() {
+                    break;
+                }
             }
         }
     }
     Ok(())
 }
 
-#[derive(Debug, Clone)]
+/// Send a tick every `period` until receiver is dropped, one missed while
+/// receiver is busy is sent once it is ready
+async fn tick(period: Duration, tick_tx: mpsc::Sender<()>) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // First tick completes immediately, loop has just polled
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if tick_tx.send(()).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[derive(Debug)]
 enum InnerCommand {
     Message(String),
+    /// Send message and reply its message id
+    TrackedMessage(String, oneshot::Sender<Option<i64>>),
+    /// Edit message text, reply whether edit is succeed
+    EditMessage(i64, String, oneshot::Sender<bool>),
     Terminate,
 }
 
-async fn async_main<'a>(matches: &ArgMatches<'a>) -> Result<()> {
-    let mut conn = sqlx::sqlite::SqliteConnection::connect("sms_client.db").await?;
-
-    let first_run =
-        sqlx::query(r#"SELECT name FROM sqlite_master WHERE type='table' AND "name"=?"#)
-            .bind(database::current::META_TABLE)
-            .fetch_all(&mut conn)
-            .await?
-            .is_empty();
-
-    if first_run {
-        let call_logs = fetch_call_log().await;
-        let messages = fetch_sms().await;
-        if let Err(ref e) = call_logs {
-            if e.is::<PermissionError>() {
-                log::error!("Fetch call log error: {}", e);
-            } else {
-                log::error!("Unknown error in fetch call log: {}", e);
-            }
-            return Err(anyhow::Error::msg("Exit due to error show above"));
+/// Create tables and mark existing records as seen in one transaction on
+/// first run, existing database should be migrated by
+/// [`database::migration::migrate`] before. Records not older than
+/// `catch_up_since` are left unseen, so [`query_loop`] forwards them. High-water
+/// is `catch_up_since`, or newest record without catch up.
+#[allow(clippy::too_many_arguments)]
+async fn prepare_database(
+    conn: &mut sqlx::sqlite::SqliteConnection,
+    termux: &dyn TermuxApi,
+    applications: &[ApplicationRule],
+    sms_boxes: &[MessageBox],
+    archive: bool,
+    cipher: &Cipher,
+    catch_up_since: Option<i64>,
+    timezone: &Tz,
+) -> Result<()> {
+    if database::migration::get_version(conn).await?.is_some() {
+        if catch_up_since.is_some() {
+            log::warn!("Database is created already, catch up is ignored");
         }
-        if let Err(ref e) = messages {
-            if e.is::<PermissionError>() {
-                log::error!("Fetch call log error: {}", e);
-            } else {
-                log::error!("Unknown error in fetch sms list: {}", e);
-            }
-            return Err(anyhow::Error::msg("Exit due to error show above"));
+        return Ok(());
+    }
+
+    let call_logs = termux.fetch_call_log_since(timezone, catch_up_since).await;
+    let messages = termux
+        .fetch_sms_since(timezone, sms_boxes, catch_up_since)
+        .await;
+    if let Err(ref e) = call_logs {
+        log::error!("Fetch call log error: {}", e);
+        return Err(anyhow::Error::msg("Exit due to error show above"));
+    }
+    if let Err(ref e) = messages {
+        log::error!("Fetch sms list error: {}", e);
+        return Err(anyhow::Error::msg("Exit due to error show above"));
+    }
+    let (call_logs, messages) = (call_logs?, messages?);
+    let notifications = if applications.is_empty() {
+        Vec::new()
+    } else {
+        termux
+            .fetch_notifications(timezone)
+            .await
+            .unwrap_or_else(|e| {
+                log::warn!("Unable to fetch notification list: {}", e);
+                Vec::new()
+            })
+    };
+    let is_seen = |timestamp: i64| catch_up_since.map_or(true, |since| timestamp < since);
+
+    let mut transaction = conn.begin().await?;
+    sqlx::query(database::current::CREATE_STATEMENTS)
+        .execute(&mut transaction)
+        .await?;
+    database::crypto::save_key(&mut transaction, cipher).await?;
+
+    let identifiers = datastructures::get_identifiers(&call_logs);
+    for (call_log, identifier) in call_logs.iter().zip(identifiers) {
+        if !is_seen(call_log.get_timestamp()) {
+            continue;
         }
-        sqlx::query(database::current::CREATE_STATEMENTS)
-            .execute(&mut conn)
-            .await?;
-        for call_log in call_logs? {
-            if call_log.get_log_type() != &CallLogType::MISSED {
-                continue;
-            }
-            sqlx::query(r#"INSERT INTO "call_logs" (?, ?)"#)
-                .bind(call_log.get_identifier())
-                .bind(call_log.get_timestamp())
-                .execute(&mut conn)
+        let identifier = cipher.identifier(&identifier);
+        if archive {
+            database::archive::insert_call_log(&mut transaction, cipher, &identifier, call_log)
                 .await?;
         }
-        for sms in messages? {
-            sqlx::query(r#"INSERT INTO "messages" (?, ?)"#)
-                .bind(sms.get_identifier())
-                .bind(sms.get_timestamp())
-                .execute(&mut conn)
-                .await?;
+        if call_log.get_log_type() != &CallLogType::MISSED {
+            continue;
         }
+        sqlx::query(r#"INSERT OR IGNORE INTO "call_logs" VALUES (?, ?)"#)
+            .bind(identifier)
+            .bind(call_log.get_timestamp())
+            .execute(&mut transaction)
+            .await?;
+    }
+    if let Some(timestamp) =
+        catch_up_since.or_else(|| call_logs.iter().map(Identifier::get_timestamp).max())
+    {
+        set_high_water(&mut transaction, CALL_LOG_HIGH_WATER, timestamp).await?;
     }
+
+    let identifiers = datastructures::get_identifiers(&messages);
+    for (sms, identifier) in messages.iter().zip(identifiers) {
+        if !is_seen(sms.get_timestamp()) {
+            continue;
+        }
+        let identifier = cipher.identifier(&identifier);
+        if archive {
+            database::archive::insert_message(&mut transaction, cipher, &identifier, sms).await?;
+        }
+        if !cipher.is_enabled() {
+            database::search::index_message(&mut transaction, &identifier, sms).await?;
+        }
+        sqlx::query(r#"INSERT OR IGNORE INTO "messages" VALUES (?, ?)"#)
+            .bind(identifier)
+            .bind(sms.get_timestamp())
+            .execute(&mut transaction)
+            .await?;
+    }
+    if let Some(timestamp) =
+        catch_up_since.or_else(|| messages.iter().map(Identifier::get_timestamp).max())
+    {
+        set_high_water(&mut transaction, SMS_HIGH_WATER, timestamp).await?;
+    }
+
+    for notification in notifications
+        .iter()
+        .filter(|notification| is_seen(notification.get_timestamp()))
+    {
+        sqlx::query(r#"INSERT OR IGNORE INTO "notifications" VALUES (?, ?)"#)
+            .bind(cipher.identifier(&notification.get_identifier()))
+            .bind(notification.get_timestamp())
+            .execute(&mut transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Timezone in configure file, detected from phone if not set
+async fn load_timezone(config: &Configure, termux: &dyn TermuxApi) -> Result<Tz> {
+    Ok(match config.get_timezone() {
+        Some(timezone) => timezone
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Unable to parse timezone {:?}: {}", timezone, e))?,
+        None => detect_timezone(termux).await.unwrap_or_else(|e| {
+            log::warn!("{}, fallback to UTC", e);
+            Tz::UTC
+        }),
+    })
+}
+
+/// `$XDG_*_HOME`, `$HOME/{home_dir}` or Termux's `$PREFIX/{prefix_dir}`
+fn get_app_dir(xdg_env: &str, home_dir: &str, prefix_dir: &str) -> Result<PathBuf> {
+    std::env::var_os(xdg_env)
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(home_dir)))
+        .or_else(|| std::env::var_os("PREFIX").map(|prefix| Path::new(&prefix).join(prefix_dir)))
+        .map(|dir| dir.join(APP_DIR))
+        .ok_or_else(|| anyhow::anyhow!("Unable to locate {} directory, HOME is not set", APP_DIR))
+}
+
+/// `--config`, config of `--profile`, or `config.toml` in working directory
+fn get_config_path<'a>(matches: &ArgMatches<'a>) -> Result<PathBuf> {
+    if let Some(path) = matches.value_of("config") {
+        return Ok(PathBuf::from(path));
+    }
+    match matches.value_of("profile") {
+        Some(profile) => {
+            Ok(get_app_dir("XDG_CONFIG_HOME", ".config", "etc")?.join(format!("{}.toml", profile)))
+        }
+        None => Ok(PathBuf::from("config.toml")),
+    }
+}
+
+/// `database` in config, or database of profile
+fn get_database_path(
+    config_path: &Path,
+    config: &Configure,
+    profile: Option<&str>,
+) -> Result<PathBuf> {
+    if let Some(database) = config.get_database() {
+        return Ok(config_path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(database));
+    }
+    Ok(get_app_dir("XDG_DATA_HOME", ".local/share", "var/lib")?
+        .join(format!("{}.db", profile.unwrap_or(DEFAULT_PROFILE))))
+}
+
+/// Move database of previous versions, with its WAL files, to `database_path`
+/// unless there is database already. Return whether it is moved.
+async fn migrate_legacy_database(legacy: &Path, database_path: &Path) -> Result<bool> {
+    if !legacy.exists() {
+        return Ok(false);
+    }
+    if database_path.exists() {
+        log::warn!(
+            "Ignoring {}, using {}",
+            legacy.display(),
+            database_path.display()
+        );
+        return Ok(false);
+    }
+    if let Some(parent) = database_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    for suffix in &["", "-wal", "-shm"] {
+        let from = PathBuf::from(format!("{}{}", legacy.display(), suffix));
+        if !from.exists() {
+            continue;
+        }
+        let to = PathBuf::from(format!("{}{}", database_path.display(), suffix));
+        // Rename fails if database is moved to another file system
+        if tokio::fs::rename(&from, &to).await.is_err() {
+            tokio::fs::copy(&from, &to).await?;
+            tokio::fs::remove_file(&from).await?;
+        }
+    }
+    log::info!(
+        "Moved database {} to {}",
+        legacy.display(),
+        database_path.display()
+    );
+    Ok(true)
+}
+
+/// Config and database path of selected profile
+async fn load_profile<'a>(matches: &ArgMatches<'a>) -> Result<(Configure, PathBuf)> {
+    let config_path = get_config_path(matches)?;
+    let config = Configure::load(&config_path).await?;
+    let profile = matches.value_of("profile");
+    let database_path = get_database_path(&config_path, &config, profile)?;
+    if profile.is_none() && config.get_database().is_none() {
+        migrate_legacy_database(Path::new(LEGACY_DATABASE_PATH), &database_path).await?;
+    }
+    log::debug!("Using database {}", database_path.display());
+    Ok((config, database_path))
+}
+
+/// Only daemon creates database, other subcommands fail if it is missing
+async fn open_database(path: &Path, create: bool) -> Result<sqlx::sqlite::SqliteConnection> {
+    if create && !path.exists() {
+        log::info!("Creating database {}", path.display());
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+    }
+    // `restore` relies on WAL to tell if client is running
+    SqliteConnectOptions::new()
+        .filename(path)
+        .journal_mode(SqliteJournalMode::Wal)
+        .create_if_missing(create)
+        .connect()
+        .await
+        .map_err(|e| anyhow::anyhow!("Unable to open database {}: {}", path.display(), e))
+}
+
+/// `search` subcommand, print results to stdout
+async fn search_main<'a>(matches: &ArgMatches<'a>, search_matches: &ArgMatches<'a>) -> Result<()> {
+    let (config, database_path) = load_profile(matches).await?;
+    let termux = Termux::new(config.get_termux_timeout());
+    let timezone = load_timezone(&config, &termux).await?;
+    let mut conn = open_database(&database_path, false).await?;
+    database::migration::migrate(&mut conn, database_path.to_str()).await?;
+    let cipher = unlock_database(&mut conn, &config).await?;
+    let arguments = search_matches
+        .values_of("query")
+        .map(|values| values.collect::<Vec<_>>().join(" "))
+        .unwrap_or_default();
+    println!(
+        "{}",
+        search_messages(
+            &mut conn,
+            &cipher,
+            config.is_archive_enabled(),
+            &arguments,
+            &timezone,
+        )
+        .await?
+    );
+    Ok(())
+}
+
+/// Data key of database, passphrase is read from `SMS_CLIENT_DATABASE_KEY`
+/// or `database_key_file`
+async fn unlock_database(
+    conn: &mut sqlx::sqlite::SqliteConnection,
+    config: &Configure,
+) -> Result<Cipher> {
+    let passphrase = database::crypto::read_passphrase(
+        database::crypto::KEY_ENV,
+        config.get_database_key_file().map(String::as_str),
+    )
+    .await?;
+    database::crypto::unlock(conn, passphrase.as_deref()).await
+}
+
+/// `rekey` subcommand, encrypt database or change its passphrase
+async fn rekey_main<'a>(matches: &ArgMatches<'a>, rekey_matches: &ArgMatches<'a>) -> Result<()> {
+    let (config, database_path) = load_profile(matches).await?;
+    let passphrase = database::crypto::read_passphrase(
+        database::crypto::KEY_ENV,
+        config.get_database_key_file().map(String::as_str),
+    )
+    .await?;
+    let new_passphrase = database::crypto::read_passphrase(
+        database::crypto::NEW_KEY_ENV,
+        rekey_matches.value_of("new-key-file"),
+    )
+    .await?
+    .ok_or_else(|| {
+        anyhow::anyhow!(
+            "New key is required, set {} or --new-key-file",
+            database::crypto::NEW_KEY_ENV
+        )
+    })?;
+    let mut conn = open_database(&database_path, false).await?;
+    database::migration::migrate(&mut conn, database_path.to_str()).await?;
+    database::crypto::rekey(&mut conn, passphrase.as_deref(), &new_passphrase).await?;
+    println!(
+        "Database key changed, update {} or database_key_file to new key",
+        database::crypto::KEY_ENV
+    );
+    Ok(())
+}
+
+/// Passphrase of snapshot, from `SMS_CLIENT_BACKUP_KEY` or `--key-file`
+async fn read_backup_key<'a>(matches: &ArgMatches<'a>) -> Result<Option<String>> {
+    database::crypto::read_passphrase(database::backup::KEY_ENV, matches.value_of("key-file")).await
+}
+
+/// `backup` subcommand, snapshot database while daemon is running
+async fn backup_main<'a>(matches: &ArgMatches<'a>, backup_matches: &ArgMatches<'a>) -> Result<()> {
+    let (_config, database_path) = load_profile(matches).await?;
+    let passphrase = if backup_matches.is_present("encrypt") {
+        Some(read_backup_key(backup_matches).await?.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Backup key is required, set {} or --key-file",
+                database::backup::KEY_ENV
+            )
+        })?)
+    } else {
+        None
+    };
+    let output = Path::new(backup_matches.value_of("output").unwrap());
+    let mut conn = open_database(&database_path, false).await?;
+    database::backup::backup(
+        &mut conn,
+        output,
+        backup_matches.is_present("compress"),
+        passphrase.as_deref(),
+    )
+    .await?;
+    println!("Database backed up to {}", output.display());
+    Ok(())
+}
+
+/// `restore` subcommand, replace database by snapshot
+async fn restore_main<'a>(
+    matches: &ArgMatches<'a>,
+    restore_matches: &ArgMatches<'a>,
+) -> Result<()> {
+    let (_config, database_path) = load_profile(matches).await?;
+    let passphrase = read_backup_key(restore_matches).await?;
+    let input = Path::new(restore_matches.value_of("input").unwrap());
+    database::backup::restore(&database_path, input, passphrase.as_deref()).await?;
+    println!(
+        "Database {} restored from {}",
+        database_path.display(),
+        input.display()
+    );
+    Ok(())
+}
+
+async fn async_main<'a>(matches: &ArgMatches<'a>) -> Result<()> {
+    let (config, database_path) = load_profile(matches).await?;
+    let catch_up_since = match matches.value_of("catch-up") {
+        Some(duration) => Some(
+            chrono::Utc::now().timestamp()
+                - datastructures::parse_duration(duration)?.as_secs() as i64,
+        ),
+        None => None,
+    };
+    let applications = config.get_applications()?;
+    let sms_boxes = config.get_sms_boxes()?;
+    let battery_policy = config.get_battery_policy()?;
+    let termux: SharedTermuxApi = Arc::new(Termux::new(config.get_termux_timeout()));
+    let timezone = load_timezone(&config, termux.as_ref()).await?;
+    log::info!("Using timezone {}", timezone);
+    let bot = Bot::new(
+        config.get_upstream(),
+        config.get_bot_token(),
+        config.get_owner(),
+    );
+
+    let mut conn = open_database(&database_path, true).await?;
+
+    database::migration::migrate(&mut conn, database_path.to_str()).await?;
+    let cipher = unlock_database(&mut conn, &config).await?;
+
+    prepare_database(
+        &mut conn,
+        termux.as_ref(),
+        &applications,
+        &sms_boxes,
+        config.is_archive_enabled(),
+        &cipher,
+        catch_up_since,
+        &timezone,
+    )
+    .await?;
+
     let (msg_tx, msg_rx) = mpsc::channel(1024);
     let (query_tx, query_rx) = mpsc::channel(1024);
-    let query_task = tokio::task::spawn(query_loop(conn, msg_tx.clone(), query_rx));
-    let upstream_task = tokio::task::spawn(upstream(msg_rx));
+    let (tick_tx, tick_rx) = mpsc::channel(1);
+    let (command_tx, command_rx) = mpsc::channel(1024);
+
+    if let Err(ref e) = register_commands(&bot).await {
+        log::warn!("Unable to register command menu: {:?}", e);
+    }
+
+    let query_task = tokio::task::spawn(query_loop(
+        conn,
+        termux.clone(),
+        applications,
+        sms_boxes,
+        config.is_archive_enabled(),
+        cipher.clone(),
+        RetentionPolicy::new(config.get_retention_days(), config.get_retention_max_rows()),
+        battery_policy,
+        timezone,
+        Watchdog::new(config.get_unresponsive_threshold()),
+        msg_tx.clone(),
+        tick_rx,
+        query_rx,
+    ));
+    tokio::task::spawn(tick(POLL_INTERVAL, tick_tx));
+    let command_conn = open_database(&database_path, false).await?;
+    let command_task = tokio::task::spawn(command_loop(
+        bot.clone(),
+        termux,
+        command_conn,
+        cipher,
+        config.is_archive_enabled(),
+        timezone,
+        command_rx,
+    ));
+    let upstream_task = tokio::task::spawn(upstream(bot, msg_rx));
 
     loop {
         if let Ok(Ok(_)) = tokio::time::timeout(Duration::from_millis(500), ctrl_c()).await {
@@ -272,7 +1279,9 @@ async fn async_main<'a>(matches: &ArgMatches<'a>) -> Result<()> {
     }
     query_tx.send(InnerCommand::Terminate).await?;
     msg_tx.send(InnerCommand::Terminate).await?;
+    command_tx.send(InnerCommand::Terminate).await?;
     query_task.await??;
+    command_task.await??;
     upstream_task.await??;
     Ok(())
 }
@@ -280,12 +1289,93 @@ async fn async_main<'a>(matches: &ArgMatches<'a>) -> Result<()> {
 fn main() -> Result<()> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .takes_value(true)
+                .help("Specify configure file location, default config.toml or config of profile"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .short("p")
+                .long("profile")
+                .takes_value(true)
+                .help("Use config and database of this profile under ~/.config and ~/.local/share"),
+        )
+        .arg(
+            Arg::with_name("catch-up")
+                .long("catch-up")
+                .takes_value(true)
+                .help("On first run, forward records of this duration (e.g. 12h) instead of marking them seen"),
+        )
+        .subcommand(
+            SubCommand::with_name("search")
+                .about("Search forwarded SMS")
+                .arg(Arg::with_name("query").multiple(true).required(true).help(
+                    "e.g. \"parcel code\" from:10086 since:2021-08-01 until:2021-08-31 page:2",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("rekey")
+                .about("Encrypt database or change its passphrase")
+                .arg(
+                    Arg::with_name("new-key-file")
+                        .long("new-key-file")
+                        .takes_value(true)
+                        .help("File contains new passphrase, read from SMS_CLIENT_NEW_DATABASE_KEY if not set"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Write consistent snapshot of database, client may keep running")
+                .arg(Arg::with_name("output").required(true).help("Snapshot file"))
+                .arg(
+                    Arg::with_name("compress")
+                        .long("compress")
+                        .help("Compress snapshot by gzip"),
+                )
+                .arg(
+                    Arg::with_name("encrypt")
+                        .long("encrypt")
+                        .help("Encrypt snapshot by passphrase"),
+                )
+                .arg(
+                    Arg::with_name("key-file")
+                        .long("key-file")
+                        .takes_value(true)
+                        .help("File contains passphrase of snapshot, read from SMS_CLIENT_BACKUP_KEY if not set"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Replace database by snapshot, stop client first")
+                .arg(Arg::with_name("input").required(true).help("Snapshot file"))
+                .arg(
+                    Arg::with_name("key-file")
+                        .long("key-file")
+                        .takes_value(true)
+                        .help("File contains passphrase of snapshot, read from SMS_CLIENT_BACKUP_KEY if not set"),
+                ),
+        )
         .get_matches();
 
+    env_logger::Builder::from_default_env()
+        .filter_module("sqlx", log::LevelFilter::Warn)
+        .init();
+
     tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?
-        .block_on(async_main(&matches))?;
+        .block_on(async move {
+            match matches.subcommand() {
+                ("search", Some(search_matches)) => search_main(&matches, search_matches).await,
+                ("rekey", Some(rekey_matches)) => rekey_main(&matches, rekey_matches).await,
+                ("backup", Some(backup_matches)) => backup_main(&matches, backup_matches).await,
+                ("restore", Some(restore_matches)) => restore_main(&matches, restore_matches).await,
+                _ => async_main(&matches).await,
+            }
+        })?;
 
     Ok(())
 }
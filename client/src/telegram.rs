@@ -0,0 +1,331 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of telegram-sms-termux and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+pub mod api {
+    use anyhow::Result;
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use serde_json::json;
+
+    #[derive(Deserialize, Clone, Debug)]
+    struct Response<T> {
+        ok: bool,
+        result: Option<T>,
+        description: Option<String>,
+    }
+
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct User {
+        language_code: Option<String>,
+    }
+
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct Chat {
+        id: i64,
+    }
+
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct Message {
+        message_id: i64,
+        from: Option<User>,
+        chat: Chat,
+        text: Option<String>,
+    }
+
+    impl Message {
+        pub fn get_message_id(&self) -> i64 {
+            self.message_id
+        }
+
+        pub fn get_chat_id(&self) -> i64 {
+            self.chat.id
+        }
+
+        pub fn get_text(&self) -> Option<&String> {
+            self.text.as_ref()
+        }
+
+        pub fn get_language_code(&self) -> Option<&String> {
+            self.from
+                .as_ref()
+                .and_then(|user| user.language_code.as_ref())
+        }
+    }
+
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct Update {
+        update_id: i64,
+        message: Option<Message>,
+    }
+
+    impl Update {
+        pub fn get_update_id(&self) -> i64 {
+            self.update_id
+        }
+
+        pub fn get_message(&self) -> Option<&Message> {
+            self.message.as_ref()
+        }
+    }
+
+    #[derive(Serialize, Clone, Debug)]
+    pub struct BotCommand {
+        command: &'static str,
+        description: &'static str,
+    }
+
+    impl BotCommand {
+        pub fn new(command: &'static str, description: &'static str) -> Self {
+            Self {
+                command,
+                description,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct Bot {
+        client: reqwest::Client,
+        api_base: String,
+        owner: i64,
+    }
+
+    impl Bot {
+        pub fn new(upstream: &str, token: &str, owner: i64) -> Self {
+            Self {
+                client: reqwest::Client::new(),
+                api_base: format!("{}/bot{}", upstream.trim_end_matches('/'), token),
+                owner,
+            }
+        }
+
+        pub fn get_owner(&self) -> i64 {
+            self.owner
+        }
+
+        async fn call<T: DeserializeOwned>(
+            &self,
+            method: &str,
+            payload: &serde_json::Value,
+        ) -> Result<T> {
+            let response: Response<T> = self
+                .client
+                .post(format!("{}/{}", self.api_base, method))
+                .json(payload)
+                .send()
+                .await?
+                .json()
+                .await?;
+            match response.result {
+                Some(result) if response.ok => Ok(result),
+                _ => Err(anyhow::anyhow!(
+                    "Telegram returned error in {}: {}",
+                    method,
+                    response.description.unwrap_or_default()
+                )),
+            }
+        }
+
+        pub async fn send_message(&self, chat_id: i64, text: &str) -> Result<Message> {
+            self.call(
+                "sendMessage",
+                &json!({
+                    "chat_id": chat_id,
+                    "text": text,
+                }),
+            )
+            .await
+        }
+
+        /// Telegram refuse to edit message if text is not changed, it is not
+        /// considered as an error here.
+        pub async fn edit_message_text(
+            &self,
+            chat_id: i64,
+            message_id: i64,
+            text: &str,
+        ) -> Result<()> {
+            match self
+                .call::<serde_json::Value>(
+                    "editMessageText",
+                    &json!({
+                        "chat_id": chat_id,
+                        "message_id": message_id,
+                        "text": text,
+                    }),
+                )
+                .await
+            {
+                Err(e) if !e.to_string().contains("message is not modified") => Err(e),
+                _ => Ok(()),
+            }
+        }
+
+        pub async fn get_updates(&self, offset: i64, timeout: u64) -> Result<Vec<Update>> {
+            self.call(
+                "getUpdates",
+                &json!({
+                    "offset": offset,
+                    "timeout": timeout,
+                    "allowed_updates": ["message"],
+                }),
+            )
+            .await
+        }
+
+        /// Publish command list to the owner chat, `language_code` of `None` means the
+        /// fallback list for every language without a dedicated one.
+        pub async fn set_my_commands(
+            &self,
+            commands: &[BotCommand],
+            language_code: Option<&str>,
+        ) -> Result<bool> {
+            let mut payload = json!({
+                "commands": commands,
+                "scope": {
+                    "type": "chat",
+                    "chat_id": self.owner,
+                },
+            });
+            if let Some(language_code) = language_code {
+                payload["language_code"] = json!(language_code);
+            }
+            self.call("setMyCommands", &payload).await
+        }
+    }
+}
+
+pub mod command {
+    use super::api::BotCommand;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Language {
+        English,
+        Chinese,
+    }
+
+    impl Language {
+        /// Languages which have their own translated command list
+        pub const TRANSLATED: &'static [Language] = &[Language::Chinese];
+
+        pub fn from_code(code: Option<&str>) -> Self {
+            match code {
+                Some(code) if code.starts_with("zh") => Self::Chinese,
+                _ => Self::English,
+            }
+        }
+
+        pub fn get_code(&self) -> &'static str {
+            match self {
+                Language::English => "en",
+                Language::Chinese => "zh",
+            }
+        }
+    }
+
+    /// Every command the client is able to dispatch, command menu and `/help`
+    /// are both generated from this list.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Command {
+        Help,
+        Status,
+        Search,
+        Battery,
+    }
+
+    impl Command {
+        pub const ALL: &'static [Command] = &[
+            Command::Help,
+            Command::Status,
+            Command::Search,
+            Command::Battery,
+        ];
+
+        pub fn get_name(&self) -> &'static str {
+            match self {
+                Command::Help => "help",
+                Command::Status => "status",
+                Command::Search => "search",
+                Command::Battery => "battery",
+            }
+        }
+
+        pub fn get_description(&self, language: Language) -> &'static str {
+            match (self, language) {
+                (Command::Help, Language::English) => "Show available commands",
+                (Command::Help, Language::Chinese) => "显示可用命令",
+                (Command::Status, Language::English) => "Show battery and SIM card status",
+                (Command::Status, Language::Chinese) => "显示电池与 SIM 卡状态",
+                (Command::Search, Language::English) => {
+                    "Search SMS, e.g. \"parcel code\" from:10086 since:2021-08-01 page:2"
+                }
+                (Command::Search, Language::Chinese) => {
+                    "搜索短信，例如 \"取件码\" from:10086 since:2021-08-01 page:2"
+                }
+                (Command::Battery, Language::English) => {
+                    "Battery level sparkline of last 24h (default) or given duration, e.g. 7d"
+                }
+                (Command::Battery, Language::Chinese) => {
+                    "最近 24h（默认）或指定时长的电量字符图，例如 7d"
+                }
+            }
+        }
+
+        /// Split `/command@bot_name arguments` into command and arguments,
+        /// return `None` if text is not a known command.
+        pub fn parse(text: &str) -> Option<(Self, &str)> {
+            let text = text.strip_prefix('/')?;
+            let (command, arguments) = match text.split_once(char::is_whitespace) {
+                Some((command, arguments)) => (command, arguments.trim()),
+                None => (text, ""),
+            };
+            let command = command.split('@').next().unwrap_or_default();
+            Self::ALL
+                .iter()
+                .find(|element| element.get_name() == command)
+                .map(|element| (*element, arguments))
+        }
+
+        pub fn to_bot_commands(language: Language) -> Vec<BotCommand> {
+            Self::ALL
+                .iter()
+                .map(|element| {
+                    BotCommand::new(element.get_name(), element.get_description(language))
+                })
+                .collect()
+        }
+
+        pub fn help_text(language: Language) -> String {
+            Self::ALL
+                .iter()
+                .map(|element| {
+                    format!(
+                        "/{} - {}",
+                        element.get_name(),
+                        element.get_description(language)
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+    }
+}
+
+pub use api::Bot;
+pub use command::{Command, Language};
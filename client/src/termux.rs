@@ -0,0 +1,575 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of telegram-sms-termux and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+pub mod error {
+    use std::{error::Error, fmt::Display, time::Duration};
+
+    #[derive(Debug, Clone)]
+    pub enum TermuxError {
+        /// Command is not in `PATH`, termux-api package is not installed
+        NotFound {
+            command: String,
+        },
+        /// Termux:API android application is not installed
+        ApiNotInstalled {
+            command: String,
+            stderr: String,
+        },
+        PermissionDenied {
+            command: String,
+            permission: String,
+            stderr: String,
+        },
+        MalformedJson {
+            command: String,
+            reason: String,
+            stderr: String,
+        },
+        NonZeroExit {
+            command: String,
+            status: Option<i32>,
+            stderr: String,
+        },
+        Io {
+            command: String,
+            reason: String,
+        },
+        /// Command did not finish in time and was killed, usually Termux:API
+        /// app is killed or a permission dialog is pending
+        Timeout {
+            command: String,
+            timeout: Duration,
+        },
+    }
+
+    impl TermuxError {
+        pub fn is_timeout(&self) -> bool {
+            matches!(self, TermuxError::Timeout { .. })
+        }
+    }
+
+    impl Error for TermuxError {}
+
+    impl Display for TermuxError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                TermuxError::NotFound { command } => write!(
+                    f,
+                    "{} not found, please install termux-api package",
+                    command
+                ),
+                TermuxError::ApiNotInstalled { command, stderr } => write!(
+                    f,
+                    "{} failed, please install Termux:API app (stderr: {:?})",
+                    command, stderr
+                ),
+                TermuxError::PermissionDenied {
+                    command,
+                    permission,
+                    stderr,
+                } => write!(
+                    f,
+                    "{} permission denied, please grant {} to Termux:API app (stderr: {:?})",
+                    command, permission, stderr
+                ),
+                TermuxError::MalformedJson {
+                    command,
+                    reason,
+                    stderr,
+                } => write!(
+                    f,
+                    "{} returned malformed json: {} (stderr: {:?})",
+                    command, reason, stderr
+                ),
+                TermuxError::NonZeroExit {
+                    command,
+                    status,
+                    stderr,
+                } => write!(
+                    f,
+                    "{} exited with status {:?} (stderr: {:?})",
+                    command, status, stderr
+                ),
+                TermuxError::Io { command, reason } => {
+                    write!(f, "Unable to run {}: {}", command, reason)
+                }
+                TermuxError::Timeout { command, timeout } => write!(
+                    f,
+                    "{} did not respond in {} seconds",
+                    command,
+                    timeout.as_secs()
+                ),
+            }
+        }
+    }
+}
+
+pub mod command {
+    use std::time::Duration;
+
+    use super::error::TermuxError;
+    use serde::{de::DeserializeOwned, Deserialize};
+    use tokio::process::Command;
+
+    /// Permission which termux-api requires for each command, used if termux-api
+    /// does not tell which permission is missing.
+    pub fn get_required_permission(command: &str) -> &'static str {
+        match command {
+            "termux-sms-list" => "android.permission.READ_SMS",
+            "termux-call-log" => "android.permission.READ_CALL_LOG",
+            "termux-telephony-deviceinfo" => "android.permission.READ_PHONE_STATE",
+            "termux-notification-list" => "notification access",
+            _ => "required permission",
+        }
+    }
+
+    /// Time limit of each command, long lists take more time to be read.
+    pub fn get_default_timeout(command: &str) -> Duration {
+        match command {
+            "termux-sms-list" | "termux-call-log" => Duration::from_secs(60),
+            "getprop" => Duration::from_secs(5),
+            _ => Duration::from_secs(30),
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct ApiError {
+        error: String,
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct TermuxOutput {
+        command: String,
+        stdout: String,
+        stderr: String,
+    }
+
+    impl TermuxOutput {
+        /// Check error object which termux-api print instead of result
+        pub fn new(command: &str, stdout: String, stderr: String) -> Result<Self, TermuxError> {
+            if let Ok(ApiError { error }) = serde_json::from_str(&stdout) {
+                if error.contains("permission") {
+                    let permission = error
+                        .rsplit(": ")
+                        .next()
+                        .filter(|s| s.starts_with("android.permission."))
+                        .unwrap_or_else(|| get_required_permission(command))
+                        .to_string();
+                    return Err(TermuxError::PermissionDenied {
+                        command: command.to_string(),
+                        permission,
+                        stderr: error,
+                    });
+                }
+                return Err(TermuxError::NonZeroExit {
+                    command: command.to_string(),
+                    status: None,
+                    stderr: error,
+                });
+            }
+            Ok(Self {
+                command: command.to_string(),
+                stdout,
+                stderr,
+            })
+        }
+
+        pub fn get_stdout(&self) -> &str {
+            &self.stdout
+        }
+
+        pub fn malformed<E: std::fmt::Display>(&self, reason: E) -> TermuxError {
+            TermuxError::MalformedJson {
+                command: self.command.clone(),
+                reason: reason.to_string(),
+                stderr: self.stderr.clone(),
+            }
+        }
+
+        pub fn parse<T: DeserializeOwned>(&self) -> Result<T, TermuxError> {
+            serde_json::from_str(&self.stdout).map_err(|e| self.malformed(e))
+        }
+    }
+
+    fn is_api_not_installed(stderr: &str) -> bool {
+        stderr.contains("com.termux.api")
+            && (stderr.contains("not found")
+                || stderr.contains("unable to resolve")
+                || stderr.contains("does not exist"))
+    }
+
+    /// Child process is killed if it is dropped before finished, e.g. by timeout.
+    pub async fn run(command: &str, args: &[&str]) -> Result<TermuxOutput, TermuxError> {
+        let output = Command::new(command)
+            .args(args)
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => TermuxError::NotFound {
+                    command: command.to_string(),
+                },
+                _ => TermuxError::Io {
+                    command: command.to_string(),
+                    reason: e.to_string(),
+                },
+            })?;
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        if is_api_not_installed(&stderr) {
+            return Err(TermuxError::ApiNotInstalled {
+                command: command.to_string(),
+                stderr,
+            });
+        }
+        if !output.status.success() {
+            return Err(TermuxError::NonZeroExit {
+                command: command.to_string(),
+                status: output.status.code(),
+                stderr,
+            });
+        }
+        TermuxOutput::new(command, stdout, stderr)
+    }
+}
+
+pub mod api {
+    use std::{collections::HashMap, convert::TryFrom, sync::Arc, time::Duration};
+
+    use super::{
+        command::{get_default_timeout, TermuxOutput},
+        error::TermuxError,
+    };
+    use crate::datastructures::{
+        BatteryStatus, CallLog, Identifier, Message, MessageBox, Notification, RawCallLogList,
+        RawDeviceInfo, RawMessageList, RawNotificationList,
+    };
+    use async_trait::async_trait;
+    use chrono_tz::Tz;
+
+    /// Number of records requested by each `termux-sms-list` or
+    /// `termux-call-log` call
+    pub const PAGE_SIZE: usize = 50;
+    /// Stop paging even if high-water timestamp is not reached
+    pub const MAX_PAGES: usize = 20;
+
+    /// Next page is only needed if this one is full and all records of it are
+    /// not older than `high_water`.
+    fn is_next_page_required<T: Identifier>(
+        raw_len: usize,
+        page: &[T],
+        high_water: Option<i64>,
+    ) -> bool {
+        let reached = high_water.is_some_and(|high_water| {
+            page.iter()
+                .any(|record| record.get_timestamp() < high_water)
+        });
+        raw_len >= PAGE_SIZE && !reached
+    }
+
+    /// Every termux invocation goes through `run`, so backend can be replaced
+    /// in tests.
+    #[async_trait]
+    pub trait TermuxApi: Send + Sync {
+        async fn run(&self, command: &str, args: &[&str]) -> Result<TermuxOutput, TermuxError>;
+
+        fn get_timeout(&self, command: &str) -> Duration {
+            get_default_timeout(command)
+        }
+
+        /// Run command with its time limit, `run` is cancelled on expiry.
+        async fn call(&self, command: &str, args: &[&str]) -> Result<TermuxOutput, TermuxError> {
+            let timeout = self.get_timeout(command);
+            tokio::time::timeout(timeout, self.run(command, args))
+                .await
+                .map_err(|_| TermuxError::Timeout {
+                    command: command.to_string(),
+                    timeout,
+                })?
+        }
+
+        /// Fetch pages of messages of `boxes` until reaching one older than
+        /// `high_water`
+        async fn fetch_sms_since(
+            &self,
+            timezone: &Tz,
+            boxes: &[MessageBox],
+            high_water: Option<i64>,
+        ) -> Result<Vec<Message>, TermuxError> {
+            let list_type = MessageBox::get_list_type(boxes);
+            let limit = PAGE_SIZE.to_string();
+            let mut messages = Vec::new();
+            for page in 0..MAX_PAGES {
+                let offset = (page * PAGE_SIZE).to_string();
+                let raw: RawMessageList = self
+                    .call(
+                        "termux-sms-list",
+                        &["-t", list_type, "-l", &limit, "-o", &offset],
+                    )
+                    .await?
+                    .parse()?;
+                let current = raw.convert_to_vec(timezone);
+                let next = is_next_page_required(raw.len(), &current, high_water);
+                messages.extend(current);
+                if !next {
+                    break;
+                }
+            }
+            messages.retain(|message| boxes.contains(&message.get_box()));
+            Ok(messages)
+        }
+
+        /// Fetch pages of call logs until reaching one older than `high_water`
+        async fn fetch_call_log_since(
+            &self,
+            timezone: &Tz,
+            high_water: Option<i64>,
+        ) -> Result<Vec<CallLog>, TermuxError> {
+            let limit = PAGE_SIZE.to_string();
+            let mut logs = Vec::new();
+            for page in 0..MAX_PAGES {
+                let offset = (page * PAGE_SIZE).to_string();
+                let raw: RawCallLogList = self
+                    .call("termux-call-log", &["-l", &limit, "-o", &offset])
+                    .await?
+                    .parse()?;
+                let current = raw.convert_to_vec(timezone);
+                let next = is_next_page_required(raw.len(), &current, high_water);
+                logs.extend(current);
+                if !next {
+                    break;
+                }
+            }
+            Ok(logs)
+        }
+
+        async fn fetch_notifications(
+            &self,
+            timezone: &Tz,
+        ) -> Result<Vec<Notification>, TermuxError> {
+            let notifications: RawNotificationList =
+                self.call("termux-notification-list", &[]).await?.parse()?;
+            Ok(notifications.convert_to_vec(timezone))
+        }
+
+        async fn fetch_battery_status(&self) -> Result<BatteryStatus, TermuxError> {
+            let output = self.call("termux-battery-status", &[]).await?;
+            BatteryStatus::try_from(output.get_stdout()).map_err(|e| output.malformed(e))
+        }
+
+        async fn fetch_device_info(&self) -> Result<RawDeviceInfo, TermuxError> {
+            self.call("termux-telephony-deviceinfo", &[]).await?.parse()
+        }
+
+        async fn fetch_system_property(&self, name: &str) -> Result<String, TermuxError> {
+            Ok(self
+                .call("getprop", &[name])
+                .await?
+                .get_stdout()
+                .trim()
+                .to_string())
+        }
+    }
+
+    pub type SharedTermuxApi = Arc<dyn TermuxApi>;
+
+    /// Run termux-api commands on the phone
+    #[derive(Clone, Debug, Default)]
+    pub struct Termux {
+        /// Override default time limit of every command
+        timeout: Option<Duration>,
+    }
+
+    impl Termux {
+        pub fn new(timeout: Option<Duration>) -> Self {
+            Self { timeout }
+        }
+    }
+
+    #[async_trait]
+    impl TermuxApi for Termux {
+        async fn run(&self, command: &str, args: &[&str]) -> Result<TermuxOutput, TermuxError> {
+            super::command::run(command, args).await
+        }
+
+        fn get_timeout(&self, command: &str) -> Duration {
+            self.timeout.unwrap_or_else(|| get_default_timeout(command))
+        }
+    }
+
+    /// Count consecutive timeouts of each command, any other result means the
+    /// command is responding again. Commands are counted separately, so a
+    /// hung one is not hidden by others which still respond.
+    #[derive(Clone, Debug)]
+    pub struct Watchdog {
+        threshold: u32,
+        failures: HashMap<&'static str, u32>,
+    }
+
+    impl Watchdog {
+        pub fn new(threshold: u32) -> Self {
+            Self {
+                threshold: threshold.max(1),
+                failures: HashMap::new(),
+            }
+        }
+
+        /// Return alert text when `command` become unresponsive or recovered
+        pub fn record<T>(
+            &mut self,
+            command: &'static str,
+            result: &Result<T, TermuxError>,
+        ) -> Option<String> {
+            let failures = self.failures.entry(command).or_default();
+            match result {
+                Err(e) if e.is_timeout() => {
+                    *failures += 1;
+                    if *failures == self.threshold {
+                        return Some(format!(
+                            "[System Information]\ntermux-api unresponsive, {} timed out {} times in a row: {}",
+                            command, failures, e
+                        ));
+                    }
+                }
+                _ => {
+                    let alerted = *failures >= self.threshold;
+                    *failures = 0;
+                    if alerted {
+                        return Some(format!(
+                            "[System Information]\n{} is responding again",
+                            command
+                        ));
+                    }
+                }
+            }
+            None
+        }
+    }
+}
+
+pub use api::{SharedTermuxApi, Termux, TermuxApi, Watchdog};
+
+/// Scripted backend which return canned output, errors and delays
+#[cfg(test)]
+pub mod fake {
+    use std::{
+        collections::{HashMap, VecDeque},
+        sync::Mutex,
+        time::Duration,
+    };
+
+    use super::{api::TermuxApi, command::TermuxOutput, error::TermuxError};
+    use async_trait::async_trait;
+
+    #[derive(Clone, Debug)]
+    pub struct Reply {
+        delay: Duration,
+        result: Result<String, TermuxError>,
+    }
+
+    impl Reply {
+        pub fn output(stdout: &str) -> Self {
+            Self {
+                delay: Duration::from_secs(0),
+                result: Ok(stdout.to_string()),
+            }
+        }
+
+        pub fn error(error: TermuxError) -> Self {
+            Self {
+                delay: Duration::from_secs(0),
+                result: Err(error),
+            }
+        }
+
+        pub fn delay(mut self, delay: Duration) -> Self {
+            self.delay = delay;
+            self
+        }
+    }
+
+    /// Replies of each command are returned in order, the last one is repeated.
+    /// Replies scripted for command with arguments, e.g. `termux-sms-list -l 50 -o 0`,
+    /// take precedence over the ones scripted for command name only.
+    #[derive(Debug, Default)]
+    pub struct ScriptedTermux {
+        replies: Mutex<HashMap<String, VecDeque<Reply>>>,
+        timeout: Option<Duration>,
+    }
+
+    impl ScriptedTermux {
+        pub fn new() -> Self {
+            Default::default()
+        }
+
+        pub fn script(self, command: &str, reply: Reply) -> Self {
+            self.replies
+                .lock()
+                .unwrap()
+                .entry(command.to_string())
+                .or_default()
+                .push_back(reply);
+            self
+        }
+
+        pub fn timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = Some(timeout);
+            self
+        }
+    }
+
+    #[async_trait]
+    impl TermuxApi for ScriptedTermux {
+        fn get_timeout(&self, command: &str) -> Duration {
+            self.timeout
+                .unwrap_or_else(|| super::command::get_default_timeout(command))
+        }
+
+        async fn run(&self, command: &str, args: &[&str]) -> Result<TermuxOutput, TermuxError> {
+            let reply = {
+                let mut replies = self.replies.lock().unwrap();
+                let full_command = std::iter::once(command)
+                    .chain(args.iter().copied())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let queue = if replies.contains_key(&full_command) {
+                    replies.get_mut(&full_command)
+                } else {
+                    replies.get_mut(command)
+                };
+                match queue {
+                    Some(queue) if queue.len() > 1 => queue.pop_front(),
+                    Some(queue) => queue.front().cloned(),
+                    None => None,
+                }
+            };
+            let reply = match reply {
+                Some(reply) => reply,
+                None => {
+                    return Err(TermuxError::NotFound {
+                        command: command.to_string(),
+                    })
+                }
+            };
+            tokio::time::sleep(reply.delay).await;
+            TermuxOutput::new(command, reply.result?, String::new())
+        }
+    }
+}
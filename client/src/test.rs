@@ -19,16 +19,155 @@
  */
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod test {
+    use crate::database::{self, crypto::Cipher};
     use crate::datastructures::{
-        convert_string_to_timestamp, RawCallLogList, RawDeviceInfo, SIMState,
+        battery::PowerSource, convert_string_to_timestamp, device_info::SIMState, format_timestamp,
+        get_identifiers, parse_duration, BatteryMonitor, BatteryPolicy, BatteryStatus, CallLogType,
+        Configure, Identifier, MessageBox, RawCallLogList, RawDeviceInfo, RawMessageList,
+        RawNotificationList, StatusDiff,
     };
-    use crate::{fetch_battery_status, fetch_call_log, fetch_device_info, fetch_sms};
+    use crate::telegram::{Command, Language};
+    use crate::termux::{command::TermuxOutput, error::TermuxError};
+    use crate::termux::{
+        fake::{Reply, ScriptedTermux},
+        TermuxApi, Watchdog,
+    };
+    use crate::{
+        battery_chart, chart, get_database_path, is_seen, migrate_legacy_database, open_database,
+        prepare_database, query_loop, InnerCommand,
+    };
+    use chrono_tz::Tz;
+    use sqlx::{ConnectOptions, Connection};
+    use std::convert::TryFrom;
+    use std::{future::Future, sync::Arc, time::Duration};
+    use tokio::sync::mpsc;
+
+    const BATTERY_STATUS: &str = r#"{"health": "GOOD","percentage": 80,"plugged": "UNPLUGGED","status": "DISCHARGING","temperature": 30.0,"current": -1200}"#;
+
+    const DEVICE_INFO: &str = r#"
+    {
+        "data_enabled": "false","data_activity": "none","data_state": "disconnected","device_id": null,"device_software_version": "00",
+        "phone_count": 2,"phone_type": "gsm","network_operator": "","network_operator_name": "","network_country_iso": "us",
+        "network_type": "unknown","network_roaming": false,"sim_country_iso": "","sim_operator": "","sim_operator_name": "",
+        "sim_serial_number": null,"sim_subscriber_id": null,"sim_state": "ready"
+    }"#;
+
+    const SMS_LIST: &str = r#"[{"threadid": 1,"type": "inbox","read": true,"number": "10086","received": "2021-08-23 12:58:40","body": "Hello"}]"#;
+
+    fn scripted_termux() -> ScriptedTermux {
+        ScriptedTermux::new()
+            .script("termux-battery-status", Reply::output(BATTERY_STATUS))
+            .script("termux-telephony-deviceinfo", Reply::output(DEVICE_INFO))
+            .script("termux-call-log", Reply::output("[]"))
+            .script("termux-sms-list", Reply::output(SMS_LIST))
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// termux-sms-list reply of inbox messages from 10086, as (received, body)
+    fn sms_list(messages: &[(&str, &str)]) -> String {
+        let records: Vec<String> = messages
+            .iter()
+            .map(|(received, body)| {
+                format!(
+                    r#"{{"threadid": 1,"type": "inbox","read": true,"number": "10086","received": "{}","body": "{}"}}"#,
+                    received, body
+                )
+            })
+            .collect();
+        format!("[{}]", records.join(","))
+    }
+
+    /// Run query loop forwarding inbox SMS, it polls at startup and once on
+    /// each of `ticks`, then stops. Returns forwarded messages
+    async fn run_query_loop(
+        conn: sqlx::SqliteConnection,
+        termux: ScriptedTermux,
+        timezone: Tz,
+        watchdog: Watchdog,
+        ticks: usize,
+    ) -> Vec<String> {
+        let (message_tx, mut message_rx) = mpsc::channel(16);
+        let (tick_tx, tick_rx) = mpsc::channel(1);
+        let (_terminate_tx, terminate_rx) = mpsc::channel(1);
+        let task = tokio::task::spawn(query_loop(
+            conn,
+            Arc::new(termux),
+            vec![],
+            vec![MessageBox::Inbox],
+            false,
+            Cipher::default(),
+            Default::default(),
+            Default::default(),
+            timezone,
+            watchdog,
+            message_tx,
+            tick_rx,
+            terminate_rx,
+        ));
+        for _ in 0..ticks {
+            tick_tx.send(()).await.unwrap();
+        }
+        // Loop stops after last tick is consumed
+        drop(tick_tx);
+        task.await.unwrap().unwrap();
+        let mut messages = vec![];
+        while let Ok(command) = message_rx.try_recv() {
+            match command {
+                InnerCommand::Message(text) => messages.push(text),
+                _ => unreachable!(),
+            }
+        }
+        messages
+    }
+
+    async fn memory_database() -> sqlx::SqliteConnection {
+        sqlx::SqliteConnection::connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
 
     #[test]
     #[should_panic]
     fn test_time_convert() {
-        convert_string_to_timestamp("2021-08-23 24:58:40").unwrap();
+        convert_string_to_timestamp("2021-08-23 24:58:40", &Tz::UTC).unwrap();
+    }
+
+    #[test]
+    fn test_timezone_convert() {
+        let shanghai: Tz = "Asia/Shanghai".parse().unwrap();
+        assert_eq!(
+            convert_string_to_timestamp("2021-08-23 20:58:40", &shanghai).unwrap(),
+            (1629723520, 1629752320)
+        );
+        let new_york: Tz = "America/New_York".parse().unwrap();
+        // 01:30 happens twice when DST end, earlier one is EDT (UTC-4)
+        assert_eq!(
+            convert_string_to_timestamp("2021-11-07 01:30:00", &new_york)
+                .unwrap()
+                .0,
+            1636263000
+        );
+        // 02:30 does not exist when DST start
+        assert_eq!(
+            convert_string_to_timestamp("2021-03-14 02:30:00", &new_york)
+                .unwrap()
+                .0,
+            1615707000
+        );
+        assert_eq!(
+            format_timestamp(1629723520, &shanghai),
+            "2021-08-23 20:58:40 CST"
+        );
+        assert_eq!(format_timestamp(i64::MAX, &shanghai), i64::MAX.to_string());
     }
 
     #[test]
@@ -48,6 +187,347 @@ mod test {
         assert_eq!(logs.len(), 5);
     }
 
+    #[test]
+    fn test_skip_bad_records() {
+        let s = r#"
+        [
+            {"name": "","phone_number": "911","type": "VOICEMAIL","date": "2021-07-24 19:49:25","duration": "00:35"},
+            {"name": "","phone_number": "119","type": "UNKNOWN_TYPE","date": "2021-07-31 15:33:02","duration": "00:38"},
+            {"name": "","phone_number": "110","type": "NEW_TYPE","date": "2021-08-10 09:17:45","duration": "00:37"},
+            {"name": "","phone_number": "0237253888","type": "MISSED","date": "2021-08-11 24:34:31","duration": "00:38"},
+            {"name": "","phone_number": "0235636688","type": "ANSWERED_EXTERNALLY","date": "2021-08-20 10:38:48","duration": "00:36"}
+        ]
+        "#;
+
+        let logs = serde_json::from_str::<RawCallLogList>(s)
+            .unwrap()
+            .convert_to_vec(&Tz::UTC);
+
+        assert_eq!(
+            logs.iter()
+                .map(|log| log.get_log_type().clone())
+                .collect::<Vec<CallLogType>>(),
+            vec![
+                CallLogType::VOICEMAIL,
+                CallLogType::UNKNOWN,
+                CallLogType::ANSWERED_EXTERNALLY
+            ]
+        );
+        assert!(BatteryStatus::try_from("{\"error\": \"\"}").is_err());
+    }
+
+    fn battery_status(percentage: i8, status: &str) -> BatteryStatus {
+        battery_condition(percentage, status, 30.0, "GOOD")
+    }
+
+    fn battery_condition(
+        percentage: i8,
+        status: &str,
+        temperature: f32,
+        health: &str,
+    ) -> BatteryStatus {
+        BatteryStatus::try_from(
+            format!(
+                r#"{{"health": "{}","percentage": {},"plugged": "UNPLUGGED","status": "{}","temperature": {},"current": -1200}}"#,
+                health, percentage, status, temperature
+            )
+            .as_str(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_battery_thresholds() {
+        let mut monitor =
+            BatteryMonitor::new(BatteryPolicy::default(), &battery_status(52, "DISCHARGING"));
+        let mut alerts = |percentage, status| monitor.update(&battery_status(percentage, status));
+        assert_eq!(alerts(51, "DISCHARGING"), None);
+        assert_eq!(
+            alerts(49, "DISCHARGING").as_deref(),
+            Some("Battery is low: 49% (threshold 50%).")
+        );
+        // Bouncing around threshold alerts once
+        assert_eq!(alerts(51, "CHARGING"), None);
+        assert_eq!(alerts(50, "DISCHARGING"), None);
+        // Only furthest threshold is alerted when several are crossed
+        assert_eq!(
+            alerts(4, "DISCHARGING").as_deref(),
+            Some("Battery is low: 4% (threshold 5%).")
+        );
+        assert_eq!(alerts(3, "DISCHARGING"), None);
+        assert_eq!(
+            alerts(80, "CHARGING").as_deref(),
+            Some("Battery has been charged to 80% (threshold 80%).")
+        );
+        assert_eq!(alerts(79, "CHARGING"), None);
+        assert_eq!(
+            alerts(100, "FULL").as_deref(),
+            Some("Battery has been charged to 100% (threshold 100%).")
+        );
+        // Rearmed after moving back beyond hysteresis
+        assert_eq!(
+            alerts(50, "DISCHARGING").as_deref(),
+            Some("Battery is low: 50% (threshold 50%).")
+        );
+
+        // Thresholds reached before startup are not alerted
+        let mut monitor =
+            BatteryMonitor::new(BatteryPolicy::default(), &battery_status(15, "DISCHARGING"));
+        assert_eq!(monitor.update(&battery_status(14, "DISCHARGING")), None);
+
+        assert!(BatteryPolicy::new(Some(vec![101]), None, None).is_err());
+        assert!(BatteryPolicy::new(None, None, Some(-1)).is_err());
+    }
+
+    #[test]
+    fn test_battery_temperature_and_health() {
+        let policy = BatteryPolicy::default()
+            .with_temperature(Some(40.0), Some(50.0), Some(Duration::from_secs(600)))
+            .unwrap();
+        let mut monitor = BatteryMonitor::new(policy, &battery_status(80, "CHARGING"));
+        let start = std::time::Instant::now();
+        let mut check = |temperature: f32, minutes: u64| {
+            monitor.check_temperature(
+                &battery_condition(80, "CHARGING", temperature, "GOOD"),
+                start + Duration::from_secs(minutes * 60),
+            )
+        };
+        assert_eq!(check(39.9, 0), None);
+        assert_eq!(
+            check(41.0, 1).as_deref(),
+            Some("Battery temperature is high: 41.0°C (limit 40.0°C).")
+        );
+        // Bouncing around limit is suppressed by cooldown
+        assert_eq!(check(39.0, 2), None);
+        assert_eq!(check(41.0, 3), None);
+        // Escalation is alerted at once
+        assert_eq!(
+            check(50.5, 4).as_deref(),
+            Some("Battery temperature is critical: 50.5°C (limit 50.0°C).")
+        );
+        assert_eq!(check(51.0, 10), None);
+        assert!(check(51.0, 14).is_some());
+
+        let mut monitor =
+            BatteryMonitor::new(BatteryPolicy::default(), &battery_status(80, "CHARGING"));
+        assert_eq!(
+            monitor.check_health(&battery_condition(80, "CHARGING", 30.0, "GOOD")),
+            None
+        );
+        assert_eq!(
+            monitor
+                .check_health(&battery_condition(80, "CHARGING", 30.0, "OVERHEAT"))
+                .as_deref(),
+            Some("Battery health changed from GOOD to OVERHEAT.")
+        );
+        assert_eq!(
+            monitor.check_health(&battery_condition(80, "CHARGING", 30.0, "OVERHEAT")),
+            None
+        );
+
+        assert!(BatteryPolicy::default()
+            .with_temperature(Some(50.0), Some(45.0), None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_battery_history() {
+        block_on(async move {
+            use database::battery_history::{downsample, fetch, record};
+
+            let mut conn = memory_database().await;
+            sqlx::query(database::current::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            let now = chrono::Utc::now().timestamp();
+            let hours_ago = (now - 7200) / 60 * 60;
+            let days_ago = (now - 2 * 86400) / 900 * 900;
+            let records = [
+                (now - 40 * 86400, 90, "DISCHARGING"),
+                (days_ago, 60, "DISCHARGING"),
+                (days_ago + 100, 62, "DISCHARGING"),
+                (days_ago + 200, 64, "CHARGING"),
+                (hours_ago, 50, "DISCHARGING"),
+                (hours_ago + 10, 51, "DISCHARGING"),
+                (hours_ago + 20, 53, "DISCHARGING"),
+                (hours_ago + 60, 54, "DISCHARGING"),
+                (now - 10, 40, "DISCHARGING"),
+            ];
+            for (timestamp, percentage, status) in records.iter() {
+                record(&mut conn, &battery_status(*percentage, status), *timestamp)
+                    .await
+                    .unwrap();
+            }
+            downsample(&mut conn, now).await.unwrap();
+            let history = fetch(&mut conn, 0).await.unwrap();
+            assert_eq!(
+                history
+                    .iter()
+                    .map(|record| (record.timestamp, record.percentage, record.status.as_str()))
+                    .collect::<Vec<_>>(),
+                vec![
+                    (days_ago, 62, "CHARGING"),
+                    (hours_ago, 51, "DISCHARGING"),
+                    (hours_ago + 60, 54, "DISCHARGING"),
+                    (now - 10, 40, "DISCHARGING"),
+                ]
+            );
+            // Downsampled buckets are not merged again
+            downsample(&mut conn, now).await.unwrap();
+            assert_eq!(fetch(&mut conn, 0).await.unwrap(), history);
+
+            let text = battery_chart(&mut conn, "", &Tz::UTC).await.unwrap();
+            assert!(text.starts_with("[Battery]"));
+            assert!(text.contains("Level: 51% → 40% (min 40%, max 54%)"));
+            assert!(battery_chart(&mut conn, "7d", &Tz::UTC)
+                .await
+                .unwrap()
+                .contains("Level: 62% → 40%"));
+            assert!(battery_chart(&mut conn, "1m", &Tz::UTC).await.is_err());
+
+            assert_eq!(
+                chart::sparkline(&[Some(0.0), None, Some(50.0), Some(100.0)]),
+                "▁ ▅█"
+            );
+        });
+    }
+
+    #[test]
+    fn test_battery_estimate() {
+        use database::battery_history::{estimate_remaining, format_estimate, BatteryRecord};
+
+        let status = |percentage: i8, status: &str, current: i32| {
+            BatteryStatus::try_from(
+                format!(
+                    r#"{{"health": "GOOD","percentage": {},"plugged": "PLUGGED_AC","status": "{}","temperature": 30.0,"current": {}}}"#,
+                    percentage, status, current
+                )
+                .as_str(),
+            )
+            .unwrap()
+        };
+        let record = |timestamp, percentage, status: &str, current| BatteryRecord {
+            timestamp,
+            percentage,
+            status: status.to_string(),
+            plugged: "PLUGGED_AC".to_string(),
+            temperature: 30.0,
+            current,
+        };
+        // Charged 30% in 3000 seconds at 1000, then discharged 10% in 5000 seconds at -500
+        let records = vec![
+            record(0, 20, "CHARGING", 1000),
+            record(3000, 50, "CHARGING", 1000),
+            record(3060, 50, "FULL", 0),
+            record(4000, 50, "DISCHARGING", -500),
+            record(9000, 40, "DISCHARGING", -500),
+            record(9060, 40, "CHARGING", 1000),
+        ];
+        assert_eq!(
+            estimate_remaining(&records, &status(60, "CHARGING", 1000)),
+            Some(4000)
+        );
+        // Twice the current of learned session charges twice as fast
+        assert_eq!(
+            estimate_remaining(&records, &status(60, "CHARGING", 2000)),
+            Some(2000)
+        );
+        // Nonsense current falls back to learned rate
+        for current in &[0, -1000, 1_000_000] {
+            assert_eq!(
+                estimate_remaining(&records, &status(60, "CHARGING", *current)),
+                Some(4000)
+            );
+        }
+        assert_eq!(
+            estimate_remaining(&records, &status(40, "DISCHARGING", -500)),
+            Some(20000)
+        );
+        assert_eq!(
+            estimate_remaining(&records[..2], &status(40, "DISCHARGING", -500)),
+            None
+        );
+        assert_eq!(estimate_remaining(&records, &status(100, "FULL", 0)), None);
+
+        assert_eq!(
+            format_estimate(&status(60, "CHARGING", 1000), Some(4000)).as_deref(),
+            Some("Time to full: about 1h 6m")
+        );
+        assert_eq!(
+            format_estimate(&status(40, "DISCHARGING", -500), None).as_deref(),
+            Some("Time to empty: unknown")
+        );
+        assert_eq!(format_estimate(&status(100, "FULL", 0), None), None);
+    }
+
+    #[test]
+    fn test_power_source() {
+        let status = |plugged: &str, status: &str| {
+            BatteryStatus::try_from(
+                format!(
+                    r#"{{"health": "GOOD","percentage": 80,"plugged": "{}","status": "{}","temperature": 30.0,"current": 0}}"#,
+                    plugged, status
+                )
+                .as_str(),
+            )
+            .unwrap()
+        };
+        let mut current = status("PLUGGED_AC", "CHARGING").to_current_status();
+        assert_eq!(current.get_power_source(), &PowerSource::Ac);
+
+        // Power outage
+        let unplugged = status("UNPLUGGED", "DISCHARGING");
+        assert!(matches!(
+            current.not_equal(&unplugged),
+            StatusDiff::PowerSource
+        ));
+        current.update_charge_status(&unplugged);
+        assert!(matches!(current.not_equal(&unplugged), StatusDiff::Equal));
+
+        // Moved from mains to a laptop port
+        current.update_charge_status(&status("PLUGGED_AC", "CHARGING"));
+        let usb = status("PLUGGED_USB", "CHARGING");
+        assert!(matches!(current.not_equal(&usb), StatusDiff::PowerSource));
+        current.update_charge_status(&usb);
+        assert!(matches!(
+            current.not_equal(&status("PLUGGED_USB", "NOT_CHARGING")),
+            StatusDiff::ChargeStatus
+        ));
+
+        assert_eq!(
+            PowerSource::from("PLUGGED_WIRELESS").to_string(),
+            "wireless"
+        );
+        assert_eq!(
+            PowerSource::from("PLUGGED_DOCK"),
+            PowerSource::Other("PLUGGED_DOCK".to_string())
+        );
+        assert!(status("PLUGGED_AC", "CHARGING")
+            .to_string()
+            .contains("Power source: AC"));
+    }
+
+    #[test]
+    fn test_termux_api_error() {
+        let error = TermuxOutput::new(
+            "termux-sms-list",
+            r#"{"error": "Please grant the following permissions to use this command: android.permission.READ_SMS"}"#.to_string(),
+            String::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            TermuxError::PermissionDenied { ref permission, .. } if permission == "android.permission.READ_SMS"
+        ));
+
+        let output = TermuxOutput::new("termux-call-log", "[{".to_string(), String::new()).unwrap();
+        assert!(matches!(
+            output.parse::<RawCallLogList>(),
+            Err(TermuxError::MalformedJson { ref command, .. }) if command == "termux-call-log"
+        ));
+    }
+
     #[test]
     fn test_parse_device_info() {
         let s: &str = r#"
@@ -63,16 +543,1292 @@ mod test {
     }
 
     #[test]
-    fn test_paste_function() {
-        tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
+    fn test_parse_notifications() {
+        let s = r#"
+        [
+            {"id": 1,"tag": "","key": "0|com.whatsapp|1|null|10123","group": "","packageName": "com.whatsapp","title": "Alice","content": "Hello","when": "2021-08-23 12:58:40"},
+            {"id": 1,"tag": "","key": "0|com.whatsapp|1|null|10123","group": "","packageName": "com.whatsapp","title": "Alice","content": "2 new messages","when": "2021-08-23 12:58:45"}
+        ]
+        "#;
+        let notifications = serde_json::from_str::<RawNotificationList>(s)
             .unwrap()
-            .block_on(async move {
-                fetch_device_info().await.unwrap();
-                fetch_call_log().await.unwrap();
-                fetch_sms().await.unwrap();
-                fetch_battery_status().await.unwrap();
+            .convert_to_vec(&Tz::UTC);
+
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(notifications[0].get_package_name(), "com.whatsapp");
+        assert_ne!(
+            notifications[0].get_identifier(),
+            notifications[1].get_identifier()
+        );
+    }
+
+    #[test]
+    fn test_application_rules() {
+        let config: Configure = toml::from_str(
+            r#"
+            upstream = "https://api.telegram.org"
+            bot_token = ""
+            owner = 0
+
+            [[applications]]
+            package = "com.example.bank"
+            name = "Bank"
+            exclude_title = "(?i)sync complete"
+
+            [[applications]]
+            package = "com.whatsapp"
+            include_content = "^OTP"
+            "#,
+        )
+        .unwrap();
+        let rules = config.get_applications().unwrap();
+        let s = r#"
+        [
+            {"id": 1,"tag": "","key": "0|com.example.bank|1|null|10123","group": "","packageName": "com.example.bank","title": "Sync complete","content": "","when": "2021-08-23 12:58:40"},
+            {"id": 2,"tag": "","key": "0|com.example.bank|2|null|10123","group": "","packageName": "com.example.bank","title": "Payment","content": "-$10","when": "2021-08-23 12:58:40"},
+            {"id": 3,"tag": "","key": "0|com.example.bank|3|null|10123","group": "","packageName": "com.example.bank","title": "Payment","content": "-$10","when": "2021-08-23 12:58:40","ongoing": true},
+            {"id": 4,"tag": "","key": "0|com.whatsapp|4|null|10123","group": "","packageName": "com.whatsapp","title": "Alice","content": "Hello","when": "2021-08-23 12:58:40"},
+            {"id": 5,"tag": "","key": "0|com.whatsapp|5|null|10123","group": "","packageName": "com.whatsapp","title": "Alice","content": "OTP 1234","when": "2021-08-23 12:58:40"},
+            {"id": 6,"tag": "","key": "0|com.other|6|null|10123","group": "","packageName": "com.other","title": "Payment","content": "-$10","when": "2021-08-23 12:58:40"}
+        ]
+        "#;
+        let matched = serde_json::from_str::<RawNotificationList>(s)
+            .unwrap()
+            .convert_to_vec(&Tz::UTC)
+            .iter()
+            .filter_map(|n| rules.iter().find(|rule| rule.is_match(n)))
+            .map(|rule| rule.get_display_name().clone())
+            .collect::<Vec<String>>();
+
+        assert_eq!(matched, vec!["Bank", "com.whatsapp"]);
+    }
+
+    #[test]
+    fn test_group_summary() {
+        let config: Configure = toml::from_str(
+            r#"
+            upstream = "https://api.telegram.org"
+            bot_token = ""
+            owner = 0
+            applications = ["com.example.bank", "com.example.wallet"]
+            "#,
+        )
+        .unwrap();
+        let rules = config.get_applications().unwrap();
+        // Summary android posted for bundled notifications of bank, and the
+        // one wallet posted for its own group, which is listed first
+        let s = r#"
+        [
+            {"id": 2147483647,"tag": "ranker_group","key": "0|com.example.bank|2147483647|ranker_group|10123","group": "ranker_group","packageName": "com.example.bank","title": "","content": "","when": "2021-08-23 12:58:40"},
+            {"id": 11,"tag": "","key": "0|com.example.bank|11|null|10123","group": "","packageName": "com.example.bank","title": "Payment","content": "-$10","when": "2021-08-23 12:58:40"},
+            {"id": 12,"tag": "","key": "0|com.example.bank|12|null|10123","group": "","packageName": "com.example.bank","title": "Payment","content": "-$20","when": "2021-08-23 12:58:40"},
+            {"id": 0,"tag": "","key": "0|com.example.wallet|0|null|10124","group": "transactions","packageName": "com.example.wallet","title": "Wallet","content": "2 new transactions","when": "2021-08-23 12:58:41"},
+            {"id": 21,"tag": "","key": "0|com.example.wallet|21|null|10124","group": "transactions","packageName": "com.example.wallet","title": "Payment","content": "-$5","when": "2021-08-23 12:58:40"},
+            {"id": 22,"tag": "","key": "0|com.example.wallet|22|null|10124","group": "transactions","packageName": "com.example.wallet","title": "Payment","content": "-$7","when": "2021-08-23 12:58:41"},
+            {"id": 31,"tag": "","key": "0|com.example.wallet|31|null|10124","group": "alerts","packageName": "com.example.wallet","title": "Card","content": "Card locked","when": "2021-08-23 12:58:42"}
+        ]
+        "#;
+        let notifications = serde_json::from_str::<RawNotificationList>(s)
+            .unwrap()
+            .convert_to_vec(&Tz::UTC);
+        assert_eq!(
+            notifications
+                .iter()
+                .map(|n| n.is_group_summary())
+                .collect::<Vec<_>>(),
+            vec![true, false, false, true, false, false, false]
+        );
+
+        let forwarded = notifications
+            .iter()
+            .filter(|n| rules.iter().any(|rule| rule.is_match(n)))
+            .map(|n| n.get_content().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(forwarded, vec!["-$10", "-$20", "-$5", "-$7", "Card locked"]);
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(Command::parse("/help"), Some((Command::Help, "")));
+        assert_eq!(
+            Command::parse("/status@sms_bot  now "),
+            Some((Command::Status, "now"))
+        );
+        assert_eq!(Command::parse("/unknown"), None);
+        assert_eq!(Command::parse("help"), None);
+
+        let help = Command::help_text(Language::English);
+        for command in Command::ALL {
+            assert!(help.contains(&format!("/{}", command.get_name())));
+            assert_eq!(
+                Command::parse(&format!("/{}", command.get_name()))
+                    .unwrap()
+                    .0,
+                *command
+            );
+        }
+        assert_eq!(
+            Command::to_bot_commands(Language::Chinese).len(),
+            Command::ALL.len()
+        );
+    }
+
+    #[test]
+    fn test_upgrade_database() {
+        block_on(async move {
+            let mut conn = sqlx::SqliteConnection::connect("sqlite::memory:")
+                .await
+                .unwrap();
+            sqlx::query(database::v1::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            sqlx::query(database::v2::UPGRADE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            let (version,): (String,) =
+                sqlx::query_as(r#"SELECT "value" FROM "client_meta" WHERE "key" = 'version'"#)
+                    .fetch_one(&mut conn)
+                    .await
+                    .unwrap();
+            assert_eq!(version, database::v2::VERSION);
+            sqlx::query(r#"INSERT INTO "notification_messages" VALUES ('key', 1, 0)"#)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_paste_function() {
+        block_on(async move {
+            let termux = scripted_termux();
+            termux.fetch_device_info().await.unwrap();
+            termux.fetch_call_log_since(&Tz::UTC, None).await.unwrap();
+            assert_eq!(
+                termux
+                    .fetch_sms_since(&Tz::UTC, &[MessageBox::Inbox], None)
+                    .await
+                    .unwrap()
+                    .len(),
+                1
+            );
+            termux.fetch_battery_status().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_first_run() {
+        block_on(async move {
+            let termux = ScriptedTermux::new()
+                .script("termux-sms-list", Reply::output("[]"))
+                .script("termux-call-log", Reply::output("[]"))
+                .script(
+                    "termux-call-log",
+                    Reply::error(TermuxError::PermissionDenied {
+                        command: "termux-call-log".to_string(),
+                        permission: "android.permission.READ_CALL_LOG".to_string(),
+                        stderr: String::new(),
+                    })
+                    .delay(Duration::from_millis(10)),
+                );
+            // First replies are the empty lists
+            let mut conn = memory_database().await;
+            prepare_database(
+                &mut conn,
+                &termux,
+                &[],
+                &[MessageBox::Inbox],
+                false,
+                &Cipher::default(),
+                None,
+                &Tz::UTC,
+            )
+            .await
+            .unwrap();
+
+            let mut conn = memory_database().await;
+            assert!(prepare_database(
+                &mut conn,
+                &termux,
+                &[],
+                &[MessageBox::Inbox],
+                false,
+                &Cipher::default(),
+                None,
+                &Tz::UTC
+            )
+            .await
+            .is_err());
+            assert!(sqlx::query(r#"SELECT * FROM "client_meta""#)
+                .fetch_all(&mut conn)
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn test_catch_up() {
+        assert_eq!(
+            parse_duration("12h").unwrap(),
+            Duration::from_secs(12 * 3600)
+        );
+        assert_eq!(
+            parse_duration("2d").unwrap(),
+            Duration::from_secs(2 * 86400)
+        );
+        assert!(parse_duration("12").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("999999999999999999d").is_err());
+        assert!(parse_duration("18446744073709551615s").is_err());
+
+        block_on(async move {
+            let termux = ScriptedTermux::new()
+                    .script(
+                        "termux-sms-list",
+                        Reply::output(
+                            &sms_list(&[("2021-08-20 12:00:00", "Old"), ("2021-08-23 12:00:00", "New")]),
+                        ),
+                    )
+                    .script(
+                        "termux-call-log",
+                        Reply::output(
+                            r#"[{"name": "Alice","phone_number": "110","type": "MISSED","date": "2021-08-20 09:00:00","duration": "00:00"},{"name": "Alice","phone_number": "110","type": "MISSED","date": "2021-08-23 09:00:00","duration": "00:00"}]"#,
+                        ),
+                    );
+            let since = convert_string_to_timestamp("2021-08-22 00:00:00", &Tz::UTC)
+                .unwrap()
+                .0;
+            let mut conn = memory_database().await;
+            prepare_database(
+                &mut conn,
+                &termux,
+                &[],
+                &[MessageBox::Inbox],
+                true,
+                &Cipher::default(),
+                Some(since),
+                &Tz::UTC,
+            )
+            .await
+            .unwrap();
+
+            for table in &["messages", "call_logs", "message_archive", "message_search"] {
+                let (count,): (i64,) =
+                    sqlx::query_as(&format!(r#"SELECT COUNT(*) FROM "{}""#, table))
+                        .fetch_one(&mut conn)
+                        .await
+                        .unwrap();
+                assert_eq!(count, 1, "{}", table);
+            }
+            let messages = termux
+                .fetch_sms_since(&Tz::UTC, &[MessageBox::Inbox], None)
+                .await
+                .unwrap();
+            let identifiers = get_identifiers(&messages);
+            assert!(is_seen(
+                &mut conn,
+                &Cipher::default(),
+                "messages",
+                &messages[0],
+                &identifiers[0],
+                None
+            )
+            .await
+            .unwrap());
+            assert!(!is_seen(
+                &mut conn,
+                &Cipher::default(),
+                "messages",
+                &messages[1],
+                &identifiers[1],
+                None
+            )
+            .await
+            .unwrap());
+            // Records left unseen are still fetched after high water
+            let (high_water,): (String,) = sqlx::query_as(
+                r#"SELECT "value" FROM "client_meta" WHERE "key" = 'sms_high_water'"#,
+            )
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+            assert_eq!(high_water, since.to_string());
+
+            // Every record is in catch up window, older one listed later
+            // is still not forwarded
+            let termux = ScriptedTermux::new()
+                .script("termux-battery-status", Reply::output(BATTERY_STATUS))
+                .script("termux-telephony-deviceinfo", Reply::output(DEVICE_INFO))
+                .script("termux-call-log", Reply::output("[]"))
+                .script(
+                    "termux-sms-list",
+                    Reply::output(&sms_list(&[("2021-08-23 12:00:00", "New")])),
+                )
+                .script(
+                    "termux-sms-list",
+                    Reply::output(&sms_list(&[
+                        ("2021-08-23 12:00:00", "New"),
+                        ("2021-08-20 12:00:00", "Old"),
+                    ])),
+                );
+            let mut conn = memory_database().await;
+            prepare_database(
+                &mut conn,
+                &termux,
+                &[],
+                &[MessageBox::Inbox],
+                false,
+                &Cipher::default(),
+                Some(since),
+                &Tz::UTC,
+            )
+            .await
+            .unwrap();
+            // Second poll lists the older record too, it is still not forwarded
+            let messages = run_query_loop(conn, termux, Tz::UTC, Watchdog::new(3), 1).await;
+            assert_eq!(messages.len(), 1);
+            assert!(messages[0].contains("Content: New"));
+        });
+    }
+
+    #[test]
+    fn test_backup() {
+        block_on(async move {
+            let dir =
+                std::env::temp_dir().join(format!("sms_client_backup_test_{}", std::process::id()));
+            let database_path = dir.join("live.db");
+            let mut conn = open_database(&database_path, true).await.unwrap();
+            sqlx::query(database::current::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            sqlx::query(r#"INSERT INTO "messages" VALUES ('backed up', 0)"#)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+
+            let plain = dir.join("plain.db");
+            let sealed = dir.join("sealed.db.gz.enc");
+            database::backup::backup(&mut conn, &plain, false, None)
+                .await
+                .unwrap();
+            database::backup::backup(&mut conn, &sealed, true, Some("secret"))
+                .await
+                .unwrap();
+            // Existing snapshot is never overwritten
+            assert!(database::backup::backup(&mut conn, &plain, false, None)
+                .await
+                .is_err());
+            assert!(std::fs::read(&sealed)
+                .unwrap()
+                .starts_with(database::backup::MAGIC));
+            sqlx::query(r#"DELETE FROM "messages""#)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            conn.close().await.unwrap();
+
+            let restore = |input: std::path::PathBuf, passphrase: Option<&'static str>| {
+                let database_path = database_path.clone();
+                async move { database::backup::restore(&database_path, &input, passphrase).await }
+            };
+            assert!(restore(sealed.clone(), None).await.is_err());
+            assert!(restore(sealed.clone(), Some("wrong")).await.is_err());
+            // Database opened by running client is not replaced, which
+            // is detected only in WAL mode
+            let mut conn = open_database(&database_path, false).await.unwrap();
+            let (journal_mode,): (String,) = sqlx::query_as("PRAGMA journal_mode")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            assert_eq!(journal_mode, "wal");
+            assert!(restore(sealed.clone(), Some("secret")).await.is_err());
+            assert!(!dir.join("live.db.restore").exists());
+            conn.close().await.unwrap();
+            restore(sealed.clone(), Some("secret")).await.unwrap();
+            let mut conn = open_database(&database_path, false).await.unwrap();
+            let (identifier,): (String,) = sqlx::query_as(r#"SELECT "identifier" FROM "messages""#)
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            assert_eq!(identifier, "backed up");
+
+            // Snapshot of newer schema is rejected and live database is kept
+            sqlx::query(r#"UPDATE "client_meta" SET "value" = '999' WHERE "key" = 'version'"#)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            let newer = dir.join("newer.db");
+            database::backup::backup(&mut conn, &newer, false, None)
+                .await
+                .unwrap();
+            conn.close().await.unwrap();
+            assert!(restore(newer, None).await.is_err());
+            assert!(restore(plain, None).await.is_ok());
+            assert!(database_path.exists());
+            assert!(!dir.join("live.db.restore").exists());
+
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_backup_locked_source() {
+        block_on(async move {
+            let dir = std::env::temp_dir().join(format!(
+                "sms_client_backup_lock_test_{}",
+                std::process::id()
+            ));
+            let database_path = dir.join("live.db");
+            let mut conn = open_database(&database_path, true).await.unwrap();
+            sqlx::query(database::current::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            sqlx::query(r#"INSERT INTO "messages" VALUES ('committed', 0)"#)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            conn.close().await.unwrap();
+            let count_messages = |path: std::path::PathBuf| async move {
+                let mut conn = open_database(&path, false).await.unwrap();
+                let (count,): (i64,) = sqlx::query_as(r#"SELECT COUNT(*) FROM "messages""#)
+                    .fetch_one(&mut conn)
+                    .await
+                    .unwrap();
+                conn.close().await.unwrap();
+                count
+            };
+
+            // Uncommitted write of client is not in snapshot
+            let mut conn = open_database(&database_path, false).await.unwrap();
+            let mut writer = open_database(&database_path, false).await.unwrap();
+            sqlx::query("BEGIN IMMEDIATE")
+                .execute(&mut writer)
+                .await
+                .unwrap();
+            sqlx::query(r#"INSERT INTO "messages" VALUES ('uncommitted', 0)"#)
+                .execute(&mut writer)
+                .await
+                .unwrap();
+            let snapshot = dir.join("writing.db");
+            database::backup::backup(&mut conn, &snapshot, false, None)
+                .await
+                .unwrap();
+            assert_eq!(count_messages(snapshot).await, 1);
+            writer.close().await.unwrap();
+            conn.close().await.unwrap();
+
+            // Without WAL, source being written is locked until commit.
+            // Source does not wait by itself, so it is retried by backup
+            // instead of backing up an empty database.
+            let options = sqlx::sqlite::SqliteConnectOptions::new()
+                .filename(&database_path)
+                .journal_mode(sqlx::sqlite::SqliteJournalMode::Delete);
+            let mut conn = options
+                .clone()
+                .busy_timeout(Duration::from_secs(0))
+                .connect()
+                .await
+                .unwrap();
+            let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+            let locker = std::thread::spawn(move || {
+                block_on(async move {
+                    let mut conn = options.connect().await.unwrap();
+                    for statement in [
+                        "BEGIN EXCLUSIVE",
+                        r#"INSERT INTO "messages" VALUES ('locked', 0)"#,
+                    ] {
+                        sqlx::query(statement).execute(&mut conn).await.unwrap();
+                    }
+                    locked_tx.send(()).unwrap();
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    sqlx::query("COMMIT").execute(&mut conn).await.unwrap();
+                    conn.close().await.unwrap();
+                })
             });
+            locked_rx.recv().unwrap();
+            let snapshot = dir.join("locked.db");
+            database::backup::backup(&mut conn, &snapshot, false, None)
+                .await
+                .unwrap();
+            locker.join().unwrap();
+            assert_eq!(count_messages(snapshot).await, 2);
+            conn.close().await.unwrap();
+
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_query_loop() {
+        block_on(async move {
+            let mut conn = memory_database().await;
+            sqlx::query(database::current::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            // Same message should not be forwarded twice
+            let messages =
+                run_query_loop(conn, scripted_termux(), Tz::UTC, Watchdog::new(3), 1).await;
+            assert_eq!(messages.len(), 1);
+            assert!(messages[0].starts_with("[Receive SMS]\nFrom: 10086"));
+        });
+    }
+
+    #[test]
+    fn test_query_loop_startup_timeout() {
+        block_on(async move {
+            let mut conn = memory_database().await;
+            sqlx::query(database::current::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            let termux = ScriptedTermux::new()
+                .script(
+                    "termux-battery-status",
+                    Reply::output(BATTERY_STATUS).delay(Duration::from_secs(10)),
+                )
+                .script("termux-battery-status", Reply::output(BATTERY_STATUS))
+                .script("termux-telephony-deviceinfo", Reply::output(DEVICE_INFO))
+                .script("termux-call-log", Reply::output("[]"))
+                .script("termux-sms-list", Reply::output(SMS_LIST))
+                .timeout(Duration::from_millis(100));
+            // Hung termux-api at startup is alerted and client keeps polling
+            let messages = run_query_loop(conn, termux, Tz::UTC, Watchdog::new(1), 0).await;
+            assert_eq!(messages.len(), 3);
+            for (message, expected) in messages.iter().zip([
+                "termux-battery-status timed out",
+                "termux-battery-status is responding again",
+                "[Receive SMS]",
+            ]) {
+                assert!(message.contains(expected), "{}", message);
+            }
+        });
+    }
+
+    #[test]
+    fn test_legacy_high_water() {
+        block_on(async move {
+            let shanghai: Tz = "Asia/Shanghai".parse().unwrap();
+            let mut conn = memory_database().await;
+            sqlx::query(database::v1::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            // Received at 20:00 in Shanghai, stored as 20:00 UTC by old version
+            let (_, legacy_timestamp) =
+                convert_string_to_timestamp("2021-08-23 20:00:00", &shanghai).unwrap();
+            sqlx::query(r#"INSERT INTO "messages" VALUES ('legacy', ?)"#)
+                .bind(legacy_timestamp)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            database::migration::migrate(&mut conn, None).await.unwrap();
+
+            let termux = ScriptedTermux::new()
+                .script("termux-battery-status", Reply::output(BATTERY_STATUS))
+                .script("termux-telephony-deviceinfo", Reply::output(DEVICE_INFO))
+                .script("termux-call-log", Reply::output("[]"))
+                .script(
+                    "termux-sms-list",
+                    Reply::output(&sms_list(&[("2021-08-23 20:58:40", "Gap")])),
+                );
+            // Received after legacy record, although before its stored timestamp
+            let messages = run_query_loop(conn, termux, shanghai, Watchdog::new(3), 0).await;
+            assert_eq!(messages.len(), 1);
+            assert!(messages[0].contains("Content: Gap"));
+        });
+    }
+
+    #[test]
+    fn test_termux_timeout() {
+        block_on(async move {
+            let termux = scripted_termux()
+                .script(
+                    "termux-call-log",
+                    Reply::output("[]").delay(Duration::from_secs(10)),
+                )
+                .timeout(Duration::from_millis(100));
+            // First reply is not delayed
+            termux.fetch_call_log_since(&Tz::UTC, None).await.unwrap();
+            let error = termux
+                .fetch_call_log_since(&Tz::UTC, None)
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(error, TermuxError::Timeout { ref command, .. } if command == "termux-call-log")
+            );
+
+            let mut watchdog = Watchdog::new(2);
+            let battery = termux.fetch_battery_status().await;
+            assert!(watchdog
+                .record("termux-call-log", &Err::<(), _>(error.clone()))
+                .is_none());
+            // Other command responding does not hide hung one
+            assert!(watchdog.record("termux-battery-status", &battery).is_none());
+            let alert = watchdog
+                .record("termux-call-log", &Err::<(), _>(error.clone()))
+                .unwrap();
+            assert!(alert.contains("termux-api unresponsive"));
+            assert!(alert.contains("termux-call-log"));
+            // Alert only once until recovered
+            assert!(watchdog
+                .record("termux-call-log", &Err::<(), _>(error))
+                .is_none());
+            assert!(watchdog.record("termux-battery-status", &battery).is_none());
+            assert!(watchdog
+                .record("termux-call-log", &Ok::<(), TermuxError>(()))
+                .unwrap()
+                .contains("termux-call-log is responding again"));
+            assert!(watchdog
+                .record("termux-call-log", &Ok::<(), TermuxError>(()))
+                .is_none());
+        });
+    }
+
+    fn sms_records(start: usize, count: usize, day: u32) -> String {
+        let records: Vec<String> = (start..start + count)
+            .map(|i| {
+                format!(
+                    r#"{{"threadid": 1,"type": "inbox","read": true,"number": "10086","received": "2021-08-{:02} 12:00:00","body": "{}"}}"#,
+                    day, i
+                )
+            })
+            .collect();
+        records.join(",")
+    }
+
+    #[test]
+    fn test_fetch_pages() {
+        block_on(async move {
+            let high_water = convert_string_to_timestamp("2021-08-20 00:00:00", &Tz::UTC)
+                .unwrap()
+                .0;
+            // Next page is not required since second page reach high-water,
+            // third page is not scripted and would return error.
+            let termux = ScriptedTermux::new()
+                .script(
+                    "termux-sms-list -t inbox -l 50 -o 0",
+                    Reply::output(&format!("[{}]", sms_records(0, 50, 23))),
+                )
+                .script(
+                    "termux-sms-list -t inbox -l 50 -o 50",
+                    Reply::output(&format!(
+                        "[{},{}]",
+                        sms_records(50, 25, 22),
+                        sms_records(75, 25, 19)
+                    )),
+                );
+            let messages = termux
+                .fetch_sms_since(&Tz::UTC, &[MessageBox::Inbox], Some(high_water))
+                .await
+                .unwrap();
+            assert_eq!(messages.len(), 100);
+
+            // Short page means no more records
+            let termux = ScriptedTermux::new().script(
+                "termux-sms-list -t inbox -l 50 -o 0",
+                Reply::output(&format!("[{}]", sms_records(0, 3, 23))),
+            );
+            assert_eq!(
+                termux
+                    .fetch_sms_since(&Tz::UTC, &[MessageBox::Inbox], None)
+                    .await
+                    .unwrap()
+                    .len(),
+                3
+            );
+        });
+    }
+
+    #[test]
+    fn test_message_box() {
+        block_on(async move {
+            let termux = ScriptedTermux::new().script(
+                    "termux-sms-list -t all -l 50 -o 0",
+                    Reply::output(
+                        r#"[
+                        {"threadid": 1,"type": "inbox","read": false,"number": "10086","received": "2021-08-23 12:58:40","body": "Hello"},
+                        {"threadid": 1,"type": "sent","read": true,"number": "10086","received": "2021-08-23 12:59:40","body": "Hi"},
+                        {"threadid": 1,"type": "draft","read": true,"number": "10086","received": "2021-08-23 13:00:40","body": "Bye"}
+                    ]"#,
+                    ),
+                );
+            let messages = termux
+                .fetch_sms_since(&Tz::UTC, &[MessageBox::Inbox, MessageBox::Sent], None)
+                .await
+                .unwrap();
+            assert_eq!(messages.len(), 2);
+            assert!(!messages[0].is_read());
+            assert_eq!(messages[0].get_title(), "[Receive SMS]\nFrom: 10086");
+            assert_eq!(messages[1].get_box(), MessageBox::Sent);
+            assert_eq!(messages[1].get_title(), "[Sent SMS]\nTo: 10086");
+        });
+
+        let config: Configure = toml::from_str(
+            r#"
+            upstream = "https://api.telegram.org"
+            bot_token = ""
+            owner = 0
+            sms_boxes = ["inbox", "sent"]
+            "#,
+        )
+        .unwrap();
+        let boxes = config.get_sms_boxes().unwrap();
+        assert_eq!(boxes, vec![MessageBox::Inbox, MessageBox::Sent]);
+        assert_eq!(MessageBox::get_list_type(&boxes), "all");
+        assert_eq!(MessageBox::get_list_type(&[MessageBox::Inbox]), "inbox");
+    }
+
+    #[test]
+    fn test_identifier_v2() {
+        let s = r#"
+        [
+            {"name": "","phone_number": "110","type": "MISSED","date": "2021-08-10 09:17:45","duration": "00:00"},
+            {"name": "","phone_number": "110","type": "MISSED","date": "2021-08-10 09:17:45","duration": "00:00"},
+            {"name": "","phone_number": "110","type": "REJECTED","date": "2021-08-10 09:17:45","duration": "00:00"},
+            {"name": "","phone_number": "110","type": "MISSED","date": "2021-08-10 09:17:45","duration": "00:00","sim_id": 2}
+        ]"#;
+        let logs = serde_json::from_str::<RawCallLogList>(s)
+            .unwrap()
+            .convert_to_vec(&Tz::UTC);
+        // Identifier v1 collapse all of them
+        assert_eq!(logs[0].get_identifier(), logs[2].get_identifier());
+
+        let identifiers = get_identifiers(&logs);
+        assert_eq!(identifiers[0], logs[0].get_identifier_v2(0));
+        assert_eq!(identifiers[1], logs[1].get_identifier_v2(1));
+        let unique: std::collections::HashSet<_> = identifiers.iter().collect();
+        assert_eq!(unique.len(), identifiers.len());
+        // Counter only depends on exact duplicates listed before
+        assert_eq!(get_identifiers(&logs[1..])[1], identifiers[2]);
+
+        block_on(async move {
+            let mut conn = memory_database().await;
+            sqlx::query(database::v2::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            sqlx::query(r#"INSERT INTO "call_logs" VALUES (?, ?)"#)
+                .bind(logs[0].get_identifier())
+                .bind(logs[0].get_timestamp())
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            sqlx::query(database::v3::UPGRADE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            let until = logs[0].get_timestamp();
+            assert!(is_seen(
+                &mut conn,
+                &Cipher::default(),
+                "call_logs",
+                &logs[0],
+                &identifiers[0],
+                Some(until)
+            )
+            .await
+            .unwrap());
+            // Record after upgrade is only checked by identifier v2
+            assert!(!is_seen(
+                &mut conn,
+                &Cipher::default(),
+                "call_logs",
+                &logs[0],
+                &identifiers[0],
+                Some(until - 1)
+            )
+            .await
+            .unwrap());
+        });
+    }
+
+    #[test]
+    fn test_migration() {
+        block_on(async move {
+            let mut conn = memory_database().await;
+            // Not initialized database is left for first run
+            database::migration::migrate(&mut conn, None).await.unwrap();
+            assert_eq!(
+                database::migration::get_version(&mut conn).await.unwrap(),
+                None
+            );
+
+            // Backup of an in-memory database would stay in memory
+            let path =
+                std::env::temp_dir().join(format!("sms_client_test_{}.db", std::process::id()));
+            let path = path.to_str().unwrap();
+            let mut conn = sqlx::SqliteConnection::connect(&format!("sqlite://{}?mode=rwc", path))
+                .await
+                .unwrap();
+            sqlx::query(database::v1::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            database::migration::migrate(&mut conn, Some(path))
+                .await
+                .unwrap();
+            assert_eq!(
+                database::migration::get_version(&mut conn)
+                    .await
+                    .unwrap()
+                    .map(|version| version.to_string()),
+                Some(database::CURRENT_VERSION.to_string())
+            );
+            let backup = std::fs::read_dir(std::env::temp_dir())
+                .unwrap()
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .find(|entry| {
+                    entry
+                        .to_str()
+                        .is_some_and(|entry| entry.starts_with(&format!("{}.v1.", path)))
+                })
+                .unwrap();
+            std::fs::remove_file(backup).unwrap();
+
+            sqlx::query(r#"UPDATE "client_meta" SET "value" = '999' WHERE "key" = 'version'"#)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            assert!(database::migration::migrate(&mut conn, None).await.is_err());
+            conn.close().await.unwrap();
+            std::fs::remove_file(path).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_archive() {
+        block_on(async move {
+            let termux = ScriptedTermux::new()
+                    .script(
+                        "termux-sms-list",
+                        Reply::output(
+                            r#"[{"threadid": 3,"type": "inbox","read": false,"sender": "Operator","number": "10086","received": "2021-08-23 12:58:40","body": "Hello","sim_id": 1}]"#,
+                        ),
+                    )
+                    .script(
+                        "termux-call-log",
+                        Reply::output(
+                            r#"[{"name": "Alice","phone_number": "110","type": "INCOMING","date": "2021-08-10 09:17:45","duration": "00:35"}]"#,
+                        ),
+                    );
+            let mut conn = memory_database().await;
+            sqlx::query(database::current::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            let messages = termux
+                .fetch_sms_since(&Tz::UTC, &[MessageBox::Inbox], None)
+                .await
+                .unwrap();
+            let call_logs = termux.fetch_call_log_since(&Tz::UTC, None).await.unwrap();
+            for (message, identifier) in messages.iter().zip(get_identifiers(&messages)) {
+                database::archive::insert_message(
+                    &mut conn,
+                    &Cipher::default(),
+                    &identifier,
+                    message,
+                )
+                .await
+                .unwrap();
+                // Archive same message again only update it
+                database::archive::insert_message(
+                    &mut conn,
+                    &Cipher::default(),
+                    &identifier,
+                    message,
+                )
+                .await
+                .unwrap();
+            }
+            for (call_log, identifier) in call_logs.iter().zip(get_identifiers(&call_logs)) {
+                database::archive::insert_call_log(
+                    &mut conn,
+                    &Cipher::default(),
+                    &identifier,
+                    call_log,
+                )
+                .await
+                .unwrap();
+            }
+            let (box_name, contact_name, body, sim_id): (String, Option<String>, String, Option<String>) =
+                    sqlx::query_as(
+                        r#"SELECT "box", "contact_name", "body", "sim_id" FROM "message_archive" WHERE "thread_id" = 3"#,
+                    )
+                    .fetch_one(&mut conn)
+                    .await
+                    .unwrap();
+            assert_eq!(box_name, "inbox");
+            assert_eq!(contact_name.as_deref(), Some("Operator"));
+            assert_eq!(body, "Hello");
+            assert_eq!(sim_id.as_deref(), Some("1"));
+            let (call_type, contact_name): (String, String) =
+                sqlx::query_as(r#"SELECT "type", "contact_name" FROM "call_log_archive""#)
+                    .fetch_one(&mut conn)
+                    .await
+                    .unwrap();
+            assert_eq!(call_type, "INCOMING");
+            assert_eq!(contact_name, "Alice");
+        });
+    }
+
+    #[test]
+    fn test_search() {
+        use crate::database::search::{format_results, search, SearchQuery, PAGE_SIZE};
+
+        let shanghai: Tz = "Asia/Shanghai".parse().unwrap();
+        let query = SearchQuery::parse(
+            r#""parcel code" from:10086 since:2021-08-01 page:2"#,
+            &shanghai,
+        )
+        .unwrap();
+        assert_eq!(
+            format_results(&query, &[], &shanghai),
+            "[Search] No result on page 2"
+        );
+        assert!(SearchQuery::parse("page:0", &shanghai).is_err());
+        assert!(SearchQuery::parse("since:yesterday", &shanghai).is_err());
+
+        block_on(async move {
+            let mut conn = memory_database().await;
+            sqlx::query(database::current::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            let records: Vec<String> = (0..PAGE_SIZE + 2)
+                    .map(|i| {
+                        format!(
+                            r#"{{"threadid": 1,"type": "inbox","read": true,"number": "10086","received": "2021-08-{:02} 12:00:00","body": "Your parcel code is {}"}}"#,
+                            i + 1,
+                            1000 + i
+                        )
+                    })
+                    .chain(std::iter::once(
+                        r#"{"threadid": 2,"type": "inbox","read": true,"sender": "Alice","number": "123","received": "2021-08-30 12:00:00","body": "code of parcel locker"}"#.to_string(),
+                    ))
+                    .collect();
+            let termux = ScriptedTermux::new().script(
+                "termux-sms-list",
+                Reply::output(&format!("[{}]", records.join(","))),
+            );
+            let messages = termux
+                .fetch_sms_since(&Tz::UTC, &[MessageBox::Inbox], None)
+                .await
+                .unwrap();
+            for (message, identifier) in messages.iter().zip(get_identifiers(&messages)) {
+                database::search::index_message(&mut conn, &identifier, message)
+                    .await
+                    .unwrap();
+            }
+
+            // Phrase query does not match words in other order
+            let query = SearchQuery::parse(r#""parcel code""#, &Tz::UTC).unwrap();
+            let results = search(&mut conn, &Cipher::default(), false, &query)
+                .await
+                .unwrap();
+            assert_eq!(results.len(), PAGE_SIZE + 1);
+            assert!(results[0].snippet.contains("«parcel code»"));
+            assert!(format_results(&query, &results, &Tz::UTC).contains("page:2"));
+
+            let query = SearchQuery::parse("parcel page:2", &Tz::UTC).unwrap();
+            assert_eq!(
+                search(&mut conn, &Cipher::default(), false, &query)
+                    .await
+                    .unwrap()
+                    .len(),
+                3
+            );
+
+            let query = SearchQuery::parse("code from:alice", &Tz::UTC).unwrap();
+            let results = search(&mut conn, &Cipher::default(), false, &query)
+                .await
+                .unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].contact_name.as_deref(), Some("Alice"));
+            // Wildcards in sender are matched literally
+            for sender in &["%", "1_0"] {
+                let query = SearchQuery::parse(&format!("from:{}", sender), &Tz::UTC).unwrap();
+                assert!(search(&mut conn, &Cipher::default(), false, &query)
+                    .await
+                    .unwrap()
+                    .is_empty());
+            }
+
+            let query = SearchQuery::parse("since:2021-08-02 until:2021-08-03", &Tz::UTC).unwrap();
+            assert_eq!(
+                search(&mut conn, &Cipher::default(), false, &query)
+                    .await
+                    .unwrap()
+                    .len(),
+                2
+            );
+
+            // Malformed FTS5 query is reported instead of panic
+            let query = SearchQuery::parse(r#""unclosed"#, &Tz::UTC).unwrap();
+            assert!(search(&mut conn, &Cipher::default(), false, &query)
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn test_encryption() {
+        use crate::database::crypto::{rekey, save_key, unlock};
+        use crate::database::search::{search, SearchQuery};
+
+        block_on(async move {
+            // Key is generated for new database
+            let mut conn = memory_database().await;
+            let cipher = unlock(&mut conn, Some("secret")).await.unwrap();
+            assert!(cipher.is_enabled());
+            sqlx::query(database::current::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            save_key(&mut conn, &cipher).await.unwrap();
+            let sealed = cipher.seal("Hello");
+            assert_ne!(sealed, "Hello");
+            assert_ne!(sealed, cipher.seal("Hello"));
+            assert_eq!(cipher.open(&sealed).unwrap(), "Hello");
+            assert!(unlock(&mut conn, Some("wrong"))
+                .await
+                .err()
+                .unwrap()
+                .to_string()
+                .contains("Wrong database key"));
+            assert!(unlock(&mut conn, None).await.is_err());
+            let unlocked = unlock(&mut conn, Some("secret")).await.unwrap();
+            assert_eq!(unlocked.identifier("id"), cipher.identifier("id"));
+            assert_ne!(unlocked.identifier("id"), "id");
+
+            // Plain database is encrypted in place
+            let mut conn = memory_database().await;
+            sqlx::query(database::current::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            let termux = ScriptedTermux::new().script(
+                    "termux-sms-list",
+                    Reply::output(
+                        r#"[{"threadid": 3,"type": "inbox","read": false,"sender": "Operator","number": "10086","received": "2021-08-23 12:58:40","body": "Your parcel code is 1234"}]"#,
+                    ),
+                );
+            let messages = termux
+                .fetch_sms_since(&Tz::UTC, &[MessageBox::Inbox], None)
+                .await
+                .unwrap();
+            let identifier = get_identifiers(&messages).remove(0);
+            database::archive::insert_message(
+                &mut conn,
+                &Cipher::default(),
+                &identifier,
+                &messages[0],
+            )
+            .await
+            .unwrap();
+            database::search::index_message(&mut conn, &identifier, &messages[0])
+                .await
+                .unwrap();
+            sqlx::query(r#"INSERT INTO "messages" VALUES (?, ?)"#)
+                .bind(&identifier)
+                .bind(messages[0].get_timestamp())
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            assert!(unlock(&mut conn, Some("secret")).await.is_err());
+            rekey(&mut conn, None, "secret").await.unwrap();
+
+            let cipher = unlock(&mut conn, Some("secret")).await.unwrap();
+            let (stored,): (String,) = sqlx::query_as(r#"SELECT "identifier" FROM "messages""#)
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            assert_eq!(stored, cipher.identifier(&identifier));
+            let (body,): (String,) = sqlx::query_as(r#"SELECT "body" FROM "message_archive""#)
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            assert!(!body.contains("parcel"));
+            let (indexed,): (i64,) = sqlx::query_as(r#"SELECT COUNT(*) FROM "message_search""#)
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            assert_eq!(indexed, 0);
+            // Search falls back to decrypted archive
+            let query = SearchQuery::parse(r#""PARCEL CODE" from:operator"#, &Tz::UTC).unwrap();
+            let results = search(&mut conn, &cipher, true, &query).await.unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].snippet, "Your parcel code is 1234");
+            let query = SearchQuery::parse("locker", &Tz::UTC).unwrap();
+            assert!(search(&mut conn, &cipher, true, &query)
+                .await
+                .unwrap()
+                .is_empty());
+            // Nothing to search without archive
+            assert!(search(&mut conn, &cipher, false, &query)
+                .await
+                .unwrap_err()
+                .to_string()
+                .contains("archive = true"));
+
+            // Archive is scanned in pages
+            let messages =
+                serde_json::from_str::<RawMessageList>(&format!("[{}]", sms_records(0, 120, 24)))
+                    .unwrap()
+                    .convert_to_vec(&Tz::UTC);
+            for (message, identifier) in messages.iter().zip(get_identifiers(&messages)) {
+                database::archive::insert_message(
+                    &mut conn,
+                    &cipher,
+                    &cipher.identifier(&identifier),
+                    message,
+                )
+                .await
+                .unwrap();
+            }
+            let query = SearchQuery::parse("10086 page:24", &Tz::UTC).unwrap();
+            assert_eq!(
+                search(&mut conn, &cipher, true, &query)
+                    .await
+                    .unwrap()
+                    .len(),
+                6
+            );
+            let query = SearchQuery::parse("10086 page:25", &Tz::UTC).unwrap();
+            assert_eq!(
+                search(&mut conn, &cipher, true, &query)
+                    .await
+                    .unwrap()
+                    .len(),
+                1
+            );
+
+            // Changing passphrase keeps data key
+            assert!(rekey(&mut conn, Some("wrong"), "changed").await.is_err());
+            rekey(&mut conn, Some("secret"), "changed").await.unwrap();
+            assert!(unlock(&mut conn, Some("secret")).await.is_err());
+            let changed = unlock(&mut conn, Some("changed")).await.unwrap();
+            assert_eq!(changed.identifier(&identifier), stored);
+            assert_eq!(changed.open(&body).unwrap(), "Your parcel code is 1234");
+        });
+    }
+
+    #[test]
+    fn test_database_path() {
+        let config: Configure = toml::from_str(
+            r#"
+            upstream = "https://api.telegram.org"
+            bot_token = ""
+            owner = 0
+            database = "data/sms.db"
+            "#,
+        )
+        .unwrap();
+        // Relative path is resolved from directory of config, not working directory
+        assert_eq!(
+            get_database_path(std::path::Path::new("/etc/sms/config.toml"), &config, None).unwrap(),
+            std::path::PathBuf::from("/etc/sms/data/sms.db")
+        );
+
+        block_on(async move {
+            let dir =
+                std::env::temp_dir().join(format!("sms_client_profile_{}", std::process::id()));
+            let path = dir.join("nested").join("default.db");
+            assert!(open_database(&path, false).await.is_err());
+            assert!(!path.exists());
+            let conn = open_database(&path, true).await.unwrap();
+            conn.close().await.unwrap();
+            assert!(path.exists());
+
+            // Legacy database is moved once with its WAL files
+            let legacy = dir.join("sms_client.db");
+            let moved = dir.join("data").join("default.db");
+            std::fs::write(&legacy, "legacy").unwrap();
+            std::fs::write(dir.join("sms_client.db-wal"), "wal").unwrap();
+            assert!(migrate_legacy_database(&legacy, &moved).await.unwrap());
+            assert!(!legacy.exists());
+            assert_eq!(std::fs::read_to_string(&moved).unwrap(), "legacy");
+            assert!(dir.join("data").join("default.db-wal").exists());
+            // Existing database is never replaced
+            std::fs::write(&legacy, "newer").unwrap();
+            assert!(!migrate_legacy_database(&legacy, &moved).await.unwrap());
+            assert_eq!(std::fs::read_to_string(&moved).unwrap(), "legacy");
+            assert!(legacy.exists());
+            std::fs::remove_dir_all(dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_retention() {
+        use crate::database::retention::{prune, prune_all, RetentionPolicy};
+
+        block_on(async move {
+            let mut conn = memory_database().await;
+            sqlx::query(database::current::CREATE_STATEMENTS)
+                .execute(&mut conn)
+                .await
+                .unwrap();
+            let day = 86400;
+            for i in 0..10 {
+                sqlx::query(r#"INSERT INTO "messages" VALUES (?, ?)"#)
+                    .bind(i.to_string())
+                    .bind(i * day)
+                    .execute(&mut conn)
+                    .await
+                    .unwrap();
+            }
+            async fn count(conn: &mut sqlx::SqliteConnection) -> i64 {
+                let (count,): (i64,) = sqlx::query_as(r#"SELECT COUNT(*) FROM "messages""#)
+                    .fetch_one(conn)
+                    .await
+                    .unwrap();
+                count
+            }
+            let now = 10 * day;
+
+            // Termux still returns record of day 2, it must be kept
+            let policy = RetentionPolicy::new(Some(5), None);
+            assert_eq!(
+                prune(&mut conn, "messages", &policy, 2 * day, now)
+                    .await
+                    .unwrap(),
+                2
+            );
+            assert_eq!(
+                prune(&mut conn, "messages", &policy, i64::MAX, now)
+                    .await
+                    .unwrap(),
+                3
+            );
+            assert_eq!(count(&mut conn).await, 5);
+
+            let policy = RetentionPolicy::new(None, Some(2));
+            assert_eq!(
+                prune_all(&mut conn, &policy, &[("messages", Some(7 * day))], now)
+                    .await
+                    .unwrap(),
+                2
+            );
+            assert_eq!(count(&mut conn).await, 3);
+            let (mode,): (i64,) = sqlx::query_as("PRAGMA auto_vacuum")
+                .fetch_one(&mut conn)
+                .await
+                .unwrap();
+            assert_eq!(mode, 2);
+
+            // Unknown oldest record means table is not touched
+            assert_eq!(
+                prune_all(&mut conn, &policy, &[("messages", None)], now)
+                    .await
+                    .unwrap(),
+                0
+            );
+            assert!(!RetentionPolicy::default().is_enabled());
+        });
     }
 }